@@ -5,9 +5,15 @@ fn main() {
 
     capnpc::CompilerCommand::new()
         .file("examples-capnp/example.capnp")
+        .file("examples-capnp/annotated.capnp")
+        .file("examples-capnp/capnp/compat/json.capnp")
         .src_prefix("examples-capnp")
+        .import_path("examples-capnp")
+        .import_path("capnp")
+        .no_standard_import()
         .default_parent_module(vec!["schemas".into()])
         .run()
         .expect("failed to run capnpc");
-    println!("cargo:rerun-if-changed=examples-capnp/example.capnp");
+    println!("cargo:rerun-if-changed=examples-capnp");
+    println!("cargo:rerun-if-changed=capnp");
 }
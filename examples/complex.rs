@@ -1,4 +1,4 @@
-use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, DataEncoding, Options};
 
 mod schemas {
     pub mod example_capnp {
@@ -50,7 +50,15 @@ fn main() {
         "JSON:\n{}\n",
         serde_json::to_string(&serde_reader).expect("Failed to serialize to JSON")
     );
-    // YAML not possible because it doesn't support binary data
+    let yaml = serde_yml::to_string(&serde_reader).expect("Failed to serialize to YAML");
+    println!("YAML:\n{yaml}");
+    let options = Options::default()
+        .data_encoding(DataEncoding::Hex)
+        .flatten_groups(true);
+    let hex_json =
+        serde_json::to_string(&CapnpSerdeReader::from(root_reader).with_options(options.clone()))
+            .expect("Failed to serialize to JSON");
+    println!("JSON with hex encoded data and flattened groups:\n{hex_json}\n");
     let messagepack_msg =
         rmp_serde::to_vec(&serde_reader).expect("Failed to serialize to MessagePack");
     println!("MessagePack:\n{messagepack_msg:x?}\n",);
@@ -71,6 +79,20 @@ fn main() {
             .into_reader()
     );
 
+    let back_message =
+        CapnpSerdeBuilder::<schemas::example_capnp::complex::Owned>::deserialize_with_options(
+            // Unlike the streaming deserializer, `Value` knows the length of lists upfront
+            serde_json::from_str::<serde_json::Value>(&hex_json).expect("Failed to parse JSON"),
+            &options,
+        )
+        .expect("Failed to deserialize from JSON");
+    println!(
+        "Deserialized message via JSON with hex encoded data and flattened groups:\n{:?}\n",
+        capnp::message::TypedBuilder::from(back_message)
+            .get_root_as_reader()
+            .unwrap()
+    );
+
     let back_message: CapnpSerdeBuilder<schemas::example_capnp::complex::Owned> =
         rmp_serde::from_slice(&messagepack_msg).expect("Failed to deserialize from MessagePack");
     println!(
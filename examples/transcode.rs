@@ -12,7 +12,7 @@ fn main() {
     tracing_subscriber::fmt::init();
 
     let json = serde_json::json!({
-        "a": [1,2,3,4,5],
+        "a": "AQIDBAU=",
         "b": "hello world!",
         "c": {
             "d": 14,
@@ -1,4 +1,4 @@
-use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, Options, UnionRepresentation};
 
 mod schemas {
     pub mod example_capnp {
@@ -55,4 +55,27 @@ fn main() {
             .get_root_as_reader()
             .unwrap()
     );
+
+    for representation in [
+        UnionRepresentation::internal(),
+        UnionRepresentation::adjacent(),
+    ] {
+        let options = Options::default().union_representation(representation.clone());
+        let serde_reader = CapnpSerdeReader::from(root_reader).with_options(options.clone());
+        let json = serde_json::to_string(&serde_reader).expect("Failed to serialize to JSON");
+        println!("JSON with {representation:?}:\n{json}\n");
+
+        let back_message =
+            CapnpSerdeBuilder::<schemas::example_capnp::unions::Owned>::deserialize_with_options(
+                &mut serde_json::Deserializer::from_str(&json),
+                &options,
+            )
+            .expect("Failed to deserialize from JSON");
+        println!(
+            "Deserialized message via JSON:\n{:?}\n",
+            capnp::message::TypedBuilder::from(back_message)
+                .get_root_as_reader()
+                .unwrap()
+        );
+    }
 }
@@ -0,0 +1,189 @@
+//! Support for the annotations of `capnp/compat/json.capnp`, which the JSON codec shipped with
+//! Cap'n Proto for C++ understands as well, and of our own `capnp/capnp-serde.capnp`.
+//!
+//! The annotations are looked up by their ID, so the schema doesn't have to be compiled with any
+//! particular module layout. Generating code for `json.capnp` is only required by capnpc to resolve
+//! the types of the annotation values.
+
+use capnp::{
+    dynamic_value,
+    schema::{AnnotationList, Enumerant, Field, StructSchema},
+};
+
+use crate::{
+    DataEncoding, NamingConvention,
+    schema::{field_name, is_group},
+};
+
+/// `$Json.name`, renames a field or enumerant.
+const NAME: u64 = 0xfa5b1fd61c2e7c3d;
+/// `$Json.flatten`, writes the fields of a group or struct into the containing map.
+const FLATTEN: u64 = 0x82d3e852af0336bf;
+/// `$Json.discriminator`, names the discriminator of a union.
+const DISCRIMINATOR: u64 = 0xcfa794e8d19a0162;
+/// `$Json.base64`, encodes a Data field as a base64 string.
+const BASE64: u64 = 0xd7d879450a253e4b;
+/// `$Json.hex`, encodes a Data field as a hex string.
+const HEX: u64 = 0xf061e22f0ae5c7b5;
+/// `$required`, the field has to be present on input.
+const REQUIRED: u64 = 0xbb6a191fe813f6c6;
+/// `$requireAll`, every non-union field of the struct or group has to be present on input.
+const REQUIRE_ALL: u64 = 0x83cdb05a77127c0e;
+/// `$requireMember`, a member of the union has to be given on input.
+const REQUIRE_MEMBER: u64 = 0xc229f07be7c80907;
+/// `$typeField`, names the field holding the type of an `AnyPointer` field.
+const TYPE_FIELD: u64 = 0xa8f2c6d4e91b3057;
+
+/// The `$Json.discriminator` options of a union.
+pub(crate) struct Discriminator {
+    /// The key of the discriminator field, if given.
+    pub(crate) name: Option<&'static str>,
+    /// The key of the field holding the value of the active member, if given.
+    pub(crate) value_name: Option<&'static str>,
+}
+
+/// The name of a field in the serialized map, as given by `$Json.name` or converted from the name
+/// declared in the schema.
+pub(crate) fn field_key(field: Field, convention: NamingConvention) -> capnp::Result<&'static str> {
+    match text(field.get_annotations()?, NAME)? {
+        Some(name) => Ok(name),
+        None => Ok(convention.convert(field_name(field)?)),
+    }
+}
+
+/// The name of an enumerant, as given by `$Json.name` or converted from the name declared in the
+/// schema.
+pub(crate) fn enumerant_name(
+    enumerant: Enumerant,
+    convention: NamingConvention,
+) -> capnp::Result<&'static str> {
+    match text(enumerant.get_annotations()?, NAME)? {
+        Some(name) => Ok(name),
+        None => Ok(convention.convert(enumerant.get_proto().get_name()?.to_str()?)),
+    }
+}
+
+/// The key prefix if the group or struct field is annotated with `$Json.flatten`.
+pub(crate) fn flatten_prefix(field: Field) -> capnp::Result<Option<&'static str>> {
+    let Some(annotation) = find(field, FLATTEN)? else {
+        return Ok(None);
+    };
+    let dynamic_value::Reader::Struct(options) = annotation else {
+        return Err(capnp::Error::failed(
+            "$Json.flatten has an unexpected type".to_owned(),
+        ));
+    };
+    Ok(Some(as_text(options.get_named("prefix")?)?))
+}
+
+/// The `$Json.discriminator` of the union in the given struct or group.
+///
+/// `field` is the group the union belongs to, if any. For the anonymous union of a struct, the
+/// annotation is attached to the struct itself.
+pub(crate) fn discriminator(
+    schema: StructSchema,
+    field: Option<Field>,
+) -> capnp::Result<Option<Discriminator>> {
+    let Some(annotation) = find_on_struct(schema, field, DISCRIMINATOR)? else {
+        return Ok(None);
+    };
+    let dynamic_value::Reader::Struct(options) = annotation else {
+        return Err(capnp::Error::failed(
+            "$Json.discriminator has an unexpected type".to_owned(),
+        ));
+    };
+    let optional_text = |name| -> capnp::Result<Option<&'static str>> {
+        if options.has_named(name)? {
+            Ok(Some(as_text(options.get_named(name)?)?))
+        } else {
+            Ok(None)
+        }
+    };
+    Ok(Some(Discriminator {
+        name: optional_text("name")?,
+        value_name: optional_text("valueName")?,
+    }))
+}
+
+/// The string encoding of a Data field requested by `$Json.base64` or `$Json.hex`.
+pub(crate) fn data_encoding(field: Field) -> capnp::Result<Option<DataEncoding>> {
+    let annotations = field.get_annotations()?;
+    if annotations.find(BASE64).is_some() {
+        Ok(Some(DataEncoding::Base64))
+    } else if annotations.find(HEX).is_some() {
+        Ok(Some(DataEncoding::Hex))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Whether the field is annotated with `$required`.
+pub(crate) fn is_required(field: Field) -> capnp::Result<bool> {
+    Ok(find(field, REQUIRED)?.is_some())
+}
+
+/// Whether the struct or group is annotated with `$requireAll`. `field` is the group, if any.
+pub(crate) fn requires_all(schema: StructSchema, field: Option<Field>) -> capnp::Result<bool> {
+    Ok(find_on_struct(schema, field, REQUIRE_ALL)?.is_some())
+}
+
+/// Whether the union in the given struct or group is annotated with `$requireMember`. `field` is
+/// the group the union belongs to, if any.
+pub(crate) fn requires_member(schema: StructSchema, field: Option<Field>) -> capnp::Result<bool> {
+    Ok(find_on_struct(schema, field, REQUIRE_MEMBER)?.is_some())
+}
+
+/// The name of the field holding the type of the `AnyPointer` field, if given with `$typeField`.
+pub(crate) fn type_field(field: Field) -> capnp::Result<Option<&'static str>> {
+    text(field.get_annotations()?, TYPE_FIELD)
+}
+
+/// Finds an annotation on a struct, or on a group given the group field.
+fn find_on_struct(
+    schema: StructSchema,
+    field: Option<Field>,
+    id: u64,
+) -> capnp::Result<Option<dynamic_value::Reader<'static>>> {
+    match field {
+        Some(field) => find(field, id),
+        None => schema
+            .get_annotations()?
+            .find(id)
+            .map(|annotation| annotation.get_value())
+            .transpose(),
+    }
+}
+
+/// Finds an annotation on a field. The annotations of groups may also be attached to the group
+/// node rather than the field.
+fn find(field: Field, id: u64) -> capnp::Result<Option<dynamic_value::Reader<'static>>> {
+    if let Some(annotation) = field.get_annotations()?.find(id) {
+        return Ok(Some(annotation.get_value()?));
+    }
+    if is_group(field) {
+        let capnp::introspect::TypeVariant::Struct(raw) = field.get_type().which() else {
+            return Ok(None);
+        };
+        if let Some(annotation) = StructSchema::new(raw).get_annotations()?.find(id) {
+            return Ok(Some(annotation.get_value()?));
+        }
+    }
+    Ok(None)
+}
+
+/// The value of a Text annotation.
+fn text(annotations: AnnotationList, id: u64) -> capnp::Result<Option<&'static str>> {
+    match annotations.find(id) {
+        Some(annotation) => Ok(Some(as_text(annotation.get_value()?)?)),
+        None => Ok(None),
+    }
+}
+
+fn as_text(value: dynamic_value::Reader<'static>) -> capnp::Result<&'static str> {
+    let dynamic_value::Reader::Text(text) = value else {
+        return Err(capnp::Error::failed(
+            "expected a Text annotation value".to_owned(),
+        ));
+    };
+    Ok(text.to_str()?)
+}
@@ -1,13 +1,28 @@
+use std::marker::PhantomData;
+
 use capnp::{
-    dynamic_value,
+    any_pointer, dynamic_value,
     introspect::{Introspect, TypeVariant},
-    message::TypedBuilder,
+    message::{self, Allocator, HeapAllocator, TypedBuilder},
     traits::Owned,
 };
-use serde::de::DeserializeSeed;
+use serde::de::{DeserializeSeed, IgnoredAny};
 use tracing::trace;
 
-use crate::types::{seq::SeqVisitor, structs::StructVisitor};
+use crate::{
+    Error, Options, Path,
+    error::{self, de_capnp},
+    limits, mapping,
+    schema::type_name,
+    types::{
+        data::DataVisitor,
+        nullable::NullableSeed,
+        seq::{ListSlot, SeqVisitor},
+        structs::StructVisitor,
+        text::TextVisitor,
+        unsupported,
+    },
+};
 
 /// A deserialize implementation that can be used to deserialize data encoded in a serde format into a [`TypedBuilder`].
 ///
@@ -21,12 +36,17 @@ use crate::types::{seq::SeqVisitor, structs::StructVisitor};
 /// let value = CapnpSerdeBuilder::<my_type::Owned>::deserialize(&serde_json::json!(...)).unwrap();
 /// let reader = capnp::message::TypedBuilder::from(value).get_root_as_reader().unwrap();
 /// ```
-pub struct CapnpSerdeBuilder<O: Owned> {
-    message: capnp::message::TypedBuilder<O>,
+///
+/// The message is allocated with a [`HeapAllocator`] by default. Other allocators, like a
+/// [`capnp::message::ScratchSpaceHeapAllocator`] that is reused in a loop, can be passed to
+/// [`CapnpSerdeBuilder::deserialize_with_allocator`].
+pub struct CapnpSerdeBuilder<O: Owned, A: Allocator = HeapAllocator> {
+    message: capnp::message::TypedBuilder<O, A>,
+    unknown_fields: Vec<Path>,
 }
 
-impl<O: Owned> From<CapnpSerdeBuilder<O>> for TypedBuilder<O> {
-    fn from(builder: CapnpSerdeBuilder<O>) -> Self {
+impl<O: Owned, A: Allocator> From<CapnpSerdeBuilder<O, A>> for TypedBuilder<O, A> {
+    fn from(builder: CapnpSerdeBuilder<O, A>) -> Self {
         builder.message
     }
 }
@@ -37,6 +57,126 @@ where
     for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
 {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::deserialize_root(deserializer, &Options::default())
+    }
+}
+
+impl<O> CapnpSerdeBuilder<O>
+where
+    O: Owned + Introspect + 'static,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+{
+    /// Deserializes a message like [`serde::Deserialize::deserialize`], but with the given [`Options`]
+    /// instead of the defaults. Errors are located within the message.
+    pub fn deserialize_with_options<'de, D>(
+        deserializer: D,
+        options: &Options,
+    ) -> Result<Self, Error>
+    where
+        D: serde::Deserializer<'de>,
+        D::Error: Send + Sync + 'static,
+    {
+        error::capture(
+            || Some(type_name(O::introspect())),
+            || Self::deserialize_root(deserializer, options),
+        )
+    }
+
+    /// Deserializes a message like [`CapnpSerdeBuilder::deserialize_with_options`], with a first
+    /// segment sized for an input of the given length in bytes, so that the message usually ends
+    /// up in a single segment.
+    ///
+    /// The first segment has twice as many bytes as the input, but at least the default size and
+    /// at most [`Options::max_message_words`]. A first segment of a known size can be allocated
+    /// with [`HeapAllocator::first_segment_words`] and
+    /// [`CapnpSerdeBuilder::deserialize_with_allocator`] instead.
+    pub fn deserialize_with_input_len<'de, D>(
+        deserializer: D,
+        input_len: usize,
+        options: &Options,
+    ) -> Result<Self, Error>
+    where
+        D: serde::Deserializer<'de>,
+        D::Error: Send + Sync + 'static,
+    {
+        let words = u32::try_from(input_len / 4)
+            .unwrap_or(u32::MAX)
+            .max(message::SUGGESTED_FIRST_SEGMENT_WORDS);
+        let words = match options.max_message_words {
+            Some(max) => words.min(u32::try_from(max).unwrap_or(u32::MAX)).max(1),
+            None => words,
+        };
+        let allocator = HeapAllocator::new().first_segment_words(words);
+        Self::deserialize_with_allocator(deserializer, allocator, options)
+    }
+
+    fn deserialize_root<'de, D>(deserializer: D, options: &Options) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Self::deserialize_into(TypedBuilder::<O>::new_default(), deserializer, options)
+    }
+}
+
+impl<O, A> CapnpSerdeBuilder<O, A>
+where
+    O: Owned + Introspect + 'static,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+    A: Allocator,
+{
+    /// Deserializes a message like [`CapnpSerdeBuilder::deserialize_with_options`], but allocates
+    /// its segments with the given allocator.
+    pub fn deserialize_with_allocator<'de, D>(
+        deserializer: D,
+        allocator: A,
+        options: &Options,
+    ) -> Result<Self, Error>
+    where
+        D: serde::Deserializer<'de>,
+        D::Error: Send + Sync + 'static,
+    {
+        error::capture(
+            || Some(type_name(O::introspect())),
+            || {
+                let message = TypedBuilder::new(message::Builder::new(allocator));
+                Self::deserialize_into(message, deserializer, options)
+            },
+        )
+    }
+
+    /// Merges a document into an existing message, like a JSON merge patch.
+    ///
+    /// Fields that are present in the document replace the values in the message, structs and
+    /// groups are merged recursively, and unit (`null` in JSON) clears a pointer field. Lists are
+    /// replaced or appended to as selected by [`Options::list_merge`]. Fields that are required by
+    /// annotations may be left out, as they're already set in the message.
+    pub fn merge_with_options<'de, D>(
+        message: TypedBuilder<O, A>,
+        deserializer: D,
+        options: &Options,
+    ) -> Result<Self, Error>
+    where
+        D: serde::Deserializer<'de>,
+        D::Error: Send + Sync + 'static,
+    {
+        let options = Options {
+            merging: true,
+            ..options.clone()
+        };
+        error::capture(
+            || Some(type_name(O::introspect())),
+            || Self::deserialize_into(message, deserializer, &options),
+        )
+    }
+
+    fn deserialize_into<'de, D>(
+        message: TypedBuilder<O, A>,
+        deserializer: D,
+        options: &Options,
+    ) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
@@ -45,47 +185,200 @@ where
             std::any::type_name::<O>()
         );
         let mut instance = Self {
-            message: TypedBuilder::<O>::new_default(),
+            message,
+            unknown_fields: Vec::new(),
         };
+        error::start();
+        let _budget = limits::start(options);
+        let _mappings = mapping::cache();
         {
             let ty = O::introspect();
             match ty.which() {
-                TypeVariant::Struct(_) => {
-                    let builder = instance.message.init_root();
+                TypeVariant::Struct(raw) => {
+                    let builder = if options.merging {
+                        instance.message.get_root().map_err(de_capnp)?
+                    } else {
+                        limits::allocate_struct(raw.into())?;
+                        instance.message.init_root()
+                    };
                     let seed = StructVisitor {
                         builder: builder.into(),
                         ty,
+                        group: None,
+                        options,
                     };
-                    seed.deserialize(deserializer)
-                        .inspect_err(|err| tracing::error!("{err}"))?;
+                    seed.deserialize(deserializer)?;
                 }
                 TypeVariant::List(inner_ty) => {
-                    let seed = SeqVisitor::new(inner_ty, |size| {
-                        let root = instance.message.initn_root(size).into();
-                        if let dynamic_value::Builder::List(list) = root {
-                            Ok(list)
-                        } else {
-                            Err(capnp::Error::failed("Not a list".to_owned()))
+                    let seed = SeqVisitor::new(inner_ty, options, RootSlot(&mut instance.message));
+                    seed.deserialize(deserializer)?;
+                }
+                TypeVariant::Text => {
+                    let message = &mut instance.message;
+                    let text = NullableSeed::new(TextVisitor::new(options, |text: &str| {
+                        let len = u32::try_from(text.len()).map_err(|_| too_large(text.len()))?;
+                        match message.initn_root(len).into() {
+                            dynamic_value::Builder::Text(mut builder) => {
+                                builder.push_str(text);
+                                Ok(())
+                            }
+                            _ => Err(capnp::Error::failed("Not a text".to_owned())),
                         }
-                    });
-                    seed.deserialize(deserializer)
-                        .inspect_err(|err| tracing::error!("{err}"))?;
+                    }))
+                    .deserialize(deserializer)?;
+                    match text {
+                        Some(result) => result.map_err(de_capnp)?,
+                        None => clear_root(&mut instance.message, options),
+                    }
+                }
+                TypeVariant::Data => {
+                    let message = &mut instance.message;
+                    let data = NullableSeed::new(DataVisitor::new(
+                        options.data_encoding,
+                        |data: &[u8]| {
+                            let len =
+                                u32::try_from(data.len()).map_err(|_| too_large(data.len()))?;
+                            match message.initn_root(len).into() {
+                                dynamic_value::Builder::Data(builder) => {
+                                    builder.copy_from_slice(data);
+                                    Ok(())
+                                }
+                                _ => Err(capnp::Error::failed("Not a data".to_owned())),
+                            }
+                        },
+                    ))
+                    .deserialize(deserializer)?;
+                    match data {
+                        Some(result) => result.map_err(de_capnp)?,
+                        None => clear_root(&mut instance.message, options),
+                    }
+                }
+                // There is no field naming the type of the root, so only an unset pointer can be
+                // represented
+                TypeVariant::AnyPointer => {
+                    let value =
+                        NullableSeed::new(PhantomData::<IgnoredAny>).deserialize(deserializer)?;
+                    if value.is_some() {
+                        return Err(unsupported(ty.which()));
+                    }
+                    clear_root(&mut instance.message, options);
+                }
+                TypeVariant::Capability => return Err(unsupported(ty.which())),
+                _ => {
+                    return Err(serde::de::Error::custom(format!(
+                        "{} can't be the root of a message",
+                        type_name(ty)
+                    )));
                 }
-                _ => unimplemented!(),
             }
         }
+        instance.unknown_fields = error::take_unknown_fields();
         Ok(instance)
     }
 }
 
-impl<O: Owned> AsRef<capnp::message::TypedBuilder<O>> for CapnpSerdeBuilder<O> {
-    fn as_ref(&self) -> &capnp::message::TypedBuilder<O> {
+/// Leaves the root of the message unset, which a merged message may have set before.
+fn clear_root<O: Owned, A: Allocator>(message: &mut TypedBuilder<O, A>, options: &Options) {
+    if options.merging {
+        message
+            .borrow_inner_mut()
+            .init_root::<any_pointer::Builder<'_>>()
+            .clear();
+    }
+}
+
+/// The error for a text or data root that doesn't fit into a message, which the limits reject
+/// first.
+fn too_large(len: usize) -> capnp::Error {
+    capnp::Error::failed(format!("the size of {len} bytes is too large"))
+}
+
+/// Merges a document into a struct of an existing message, like
+/// [`CapnpSerdeBuilder::merge_with_options`] does with the root of a message.
+///
+/// `O` is the generated type of the struct, e.g. `person::Owned`, which also works for structs
+/// within a message. It returns the paths of the keys that didn't belong to any field, if they
+/// were collected with [`crate::UnknownFields::Collect`].
+pub fn merge_into<'de, O, D>(
+    builder: O::Builder<'_>,
+    deserializer: D,
+    options: &Options,
+) -> Result<Vec<Path>, Error>
+where
+    O: Owned + Introspect,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+    D: serde::Deserializer<'de>,
+    D::Error: Send + Sync + 'static,
+{
+    let options = Options {
+        merging: true,
+        ..options.clone()
+    };
+    let ty = O::introspect();
+    error::capture(
+        || Some(type_name(ty)),
+        || {
+            let TypeVariant::Struct(_) = ty.which() else {
+                return Err(unsupported(ty.which()));
+            };
+            error::start();
+            let _budget = limits::start(&options);
+            let _mappings = mapping::cache();
+            let seed = StructVisitor {
+                builder: builder.into(),
+                ty,
+                group: None,
+                options: &options,
+            };
+            seed.deserialize(deserializer)?;
+            Ok::<_, D::Error>(error::take_unknown_fields())
+        },
+    )
+}
+
+/// The root of a message whose type is a list.
+struct RootSlot<'m, O: Owned, A: Allocator>(&'m mut TypedBuilder<O, A>);
+
+impl<O, A> ListSlot for RootSlot<'_, O, A>
+where
+    O: Owned,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+    A: Allocator,
+{
+    fn init(&mut self, size: u32) -> capnp::Result<capnp::dynamic_list::Builder<'_>> {
+        let root = self.0.initn_root(size).into();
+        if let dynamic_value::Builder::List(list) = root {
+            Ok(list)
+        } else {
+            Err(capnp::Error::failed("Not a list".to_owned()))
+        }
+    }
+
+    fn get(&mut self) -> capnp::Result<Option<capnp::dynamic_list::Reader<'_>>> {
+        if let dynamic_value::Builder::List(list) = self.0.get_root()?.into() {
+            Ok(Some(list.into_reader()))
+        } else {
+            Err(capnp::Error::failed("Not a list".to_owned()))
+        }
+    }
+}
+
+impl<O: Owned, A: Allocator> CapnpSerdeBuilder<O, A> {
+    /// The paths of the keys that didn't belong to any field, if they were collected with
+    /// [`crate::UnknownFields::Collect`].
+    pub fn unknown_fields(&self) -> &[Path] {
+        &self.unknown_fields
+    }
+}
+
+impl<O: Owned, A: Allocator> AsRef<capnp::message::TypedBuilder<O, A>> for CapnpSerdeBuilder<O, A> {
+    fn as_ref(&self) -> &capnp::message::TypedBuilder<O, A> {
         &self.message
     }
 }
 
-impl<O: Owned> AsMut<capnp::message::TypedBuilder<O>> for CapnpSerdeBuilder<O> {
-    fn as_mut(&mut self) -> &mut capnp::message::TypedBuilder<O> {
+impl<O: Owned, A: Allocator> AsMut<capnp::message::TypedBuilder<O, A>> for CapnpSerdeBuilder<O, A> {
+    fn as_mut(&mut self) -> &mut capnp::message::TypedBuilder<O, A> {
         &mut self.message
     }
 }
@@ -0,0 +1,246 @@
+//! The public error type, which locates a failure within the message.
+
+use std::{cell::RefCell, fmt};
+
+use capnp::introspect::Type;
+
+use crate::schema::type_name;
+
+/// An error converting a Cap'n Proto message to or from another format, with the location of the
+/// value that failed.
+///
+/// Returned by [`crate::CapnpSerdeBuilder::deserialize_with_options`] and
+/// [`crate::CapnpSerdeReader::try_serialize`]. The [`serde::Deserialize`] and
+/// [`serde::Serialize`] implementations return the error type of the format instead.
+#[derive(Debug)]
+pub struct Error {
+    path: Path,
+    expected: Option<String>,
+    cause: Cause,
+}
+
+/// The underlying error of an [`Error`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Cause {
+    /// Reading or building the Cap'n Proto message failed.
+    Capnp(capnp::Error),
+    /// The format failed, or the data doesn't match the schema.
+    Format(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// The location of a value within a message, from the root to the value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Path(Vec<Segment>);
+
+/// A step of a [`Path`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// The key of a struct field, as it appears in the format.
+    Field(String),
+    /// The index of a list element.
+    Index(u32),
+}
+
+impl Error {
+    /// The location of the value that failed.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The schema type of the value that failed, e.g. `Int32` or `List(Text)`.
+    pub fn expected(&self) -> Option<&str> {
+        self.expected.as_deref()
+    }
+
+    /// The underlying error.
+    pub fn cause(&self) -> &Cause {
+        &self.cause
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.path.0.is_empty() {
+            write!(f, "{}", self.path)?;
+            if let Some(expected) = &self.expected {
+                write!(f, " ({expected})")?;
+            }
+            write!(f, ": ")?;
+        }
+        match &self.cause {
+            Cause::Capnp(err) => write!(f, "{err}"),
+            Cause::Format(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.cause {
+            Cause::Capnp(err) => Some(err),
+            Cause::Format(err) => Some(&**err),
+        }
+    }
+}
+
+impl Path {
+    /// The segments, starting at the root.
+    pub fn segments(&self) -> &[Segment] {
+        &self.0
+    }
+
+    /// The path as a JSON Pointer (RFC 6901), e.g. `/h/2/b`.
+    pub fn to_pointer(&self) -> String {
+        let mut pointer = String::new();
+        for segment in &self.0 {
+            pointer.push('/');
+            match segment {
+                Segment::Field(key) => pointer.push_str(&key.replace('~', "~0").replace('/', "~1")),
+                Segment::Index(index) => pointer.push_str(&index.to_string()),
+            }
+        }
+        pointer
+    }
+}
+
+/// Writes the path like an accessor, e.g. `h[2].b`.
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Field(key) if index == 0 => write!(f, "{key}")?,
+                Segment::Field(key) => write!(f, ".{key}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What is known about the failure that is currently propagating. The visitors only see the error
+/// type of the format, so the location is recorded on the side while the error travels up.
+///
+/// The paths of unknown fields are completed the same way, once their containing values are done.
+#[derive(Default)]
+struct Trace {
+    /// The segments from the failing value up, in reverse.
+    path: Vec<Segment>,
+    expected: Option<String>,
+    capnp: Option<capnp::Error>,
+    /// The segments of the unknown fields seen so far, from the key up, in reverse.
+    unknown: Vec<Vec<Segment>>,
+}
+
+thread_local! {
+    static TRACE: RefCell<Trace> = RefCell::default();
+}
+
+/// Records that an error leaves the value at `segment` of the given type.
+pub(crate) fn at<E>(err: E, segment: Segment, ty: Type) -> E {
+    TRACE.with_borrow_mut(|trace| {
+        if trace.expected.is_none() {
+            trace.expected = Some(type_name(ty));
+        }
+        trace.path.push(segment);
+    });
+    err
+}
+
+/// Deserializes the value at `segment` of the given type, locating errors and unknown fields
+/// within it.
+pub(crate) fn within<T, E>(
+    segment: impl FnOnce() -> Segment,
+    ty: Type,
+    deserialize: impl FnOnce() -> Result<T, E>,
+) -> Result<T, E> {
+    let start = TRACE.with_borrow(|trace| trace.unknown.len());
+    match deserialize() {
+        Ok(value) => {
+            TRACE.with_borrow_mut(|trace| {
+                if let Some(unknown) = trace.unknown.get_mut(start..)
+                    && !unknown.is_empty()
+                {
+                    let segment = segment();
+                    for path in unknown {
+                        path.push(segment.clone());
+                    }
+                }
+            });
+            Ok(value)
+        }
+        Err(err) => Err(at(err, segment(), ty)),
+    }
+}
+
+/// Records an unknown field of the struct that is being deserialized.
+pub(crate) fn unknown_field(key: String) {
+    TRACE.with_borrow_mut(|trace| trace.unknown.push(vec![Segment::Field(key)]));
+}
+
+/// Starts a conversion, forgetting what an earlier one left behind. A conversion through the serde
+/// traits that failed leaves its trace, as nothing takes it.
+pub(crate) fn start() {
+    TRACE.take();
+}
+
+/// Takes the paths of the unknown fields seen since [`start`].
+pub(crate) fn take_unknown_fields() -> Vec<Path> {
+    TRACE.with_borrow_mut(|trace| {
+        std::mem::take(&mut trace.unknown)
+            .into_iter()
+            .map(|mut path| {
+                path.reverse();
+                Path(path)
+            })
+            .collect()
+    })
+}
+
+/// Converts an error of the message into an error of the deserializer, keeping the original.
+pub(crate) fn de_capnp<E: serde::de::Error>(err: capnp::Error) -> E {
+    let message = err.to_string();
+    keep(err);
+    E::custom(message)
+}
+
+/// Converts an error of the message into an error of the serializer, keeping the original.
+pub(crate) fn ser_capnp<E: serde::ser::Error>(err: capnp::Error) -> E {
+    let message = err.to_string();
+    keep(err);
+    E::custom(message)
+}
+
+fn keep(err: capnp::Error) {
+    TRACE.with_borrow_mut(|trace| {
+        trace.capnp.get_or_insert(err);
+    });
+}
+
+/// Runs a conversion of the root value of the given type, turning its error into an [`Error`].
+pub(crate) fn capture<T, E>(
+    expected: impl FnOnce() -> Option<String>,
+    convert: impl FnOnce() -> Result<T, E>,
+) -> Result<T, Error>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    start();
+    convert().map_err(|err| {
+        let Trace {
+            mut path,
+            expected: innermost,
+            capnp,
+            ..
+        } = TRACE.take();
+        path.reverse();
+        Error {
+            path: Path(path),
+            expected: innermost.or_else(expected),
+            cause: match capnp {
+                Some(err) => Cause::Capnp(err),
+                None => Cause::Format(Box::new(err)),
+            },
+        }
+    })
+}
@@ -20,7 +20,7 @@
 //!
 //! Since Cap’n Proto uses arena-style memory allocation and builds the message in-place, it fundamentally requires you to know the size of lists ahead of time. There’s no real way around this with the official Rust capnp crate.
 //!
-//! Many deserializers supply the array/list size upfront as a hint to the decoder, which solves the problem. However, other decoders do not. There's a workaround implemented for everything except lists and structs, which involves an extra copy of the whole list (via Rust's `Vec<T>`).
+//! Many deserializers supply the array/list size upfront as a hint to the decoder, which solves the problem. However, other decoders do not. Without a hint, the elements are collected first, which involves an extra copy of the whole list: values are kept in a `Vec<T>`, and structs and lists are buffered in a self-describing form, so a format without a hint has to be self-describing for them. A hint that turns out to be wrong is recovered from by allocating the list again with the elements read so far, at the cost of a copy and of the space of the first list, which stays in the message.
 //!
 //! ## Examples
 //!
@@ -35,9 +35,23 @@
 //!
 //! Licensed under either of Apache License, Version 2.0 or MIT license at your option.
 
+mod annotations;
 mod deserialize;
+mod error;
+mod limits;
+mod mapping;
+mod naming;
+mod options;
+mod registry;
+mod schema;
 mod serialize;
 mod types;
 
-pub use deserialize::CapnpSerdeBuilder;
+pub use deserialize::{CapnpSerdeBuilder, merge_into};
+pub use error::{Cause, Error, Path, Segment};
+pub use options::{
+    DataEncoding, EnumRepresentation, ListMerge, NamingConvention, NonFiniteFloats,
+    NumericOverflow, Options, UnionRepresentation, UnknownFields,
+};
+pub use registry::TypeRegistry;
 pub use serialize::CapnpSerdeReader;
@@ -0,0 +1,200 @@
+//! Limits on the resources that a deserialization may use, for untrusted input.
+//!
+//! The limits of the running deserialization and its usage are kept on the side, like the
+//! [`crate::error`] trace, so that every visitor can check them before it allocates.
+
+use std::cell::Cell;
+
+use capnp::{
+    introspect::{Type, TypeVariant},
+    schema::StructSchema,
+};
+use serde::de::Error;
+
+use crate::{Options, error::de_capnp};
+
+/// The most elements a list can have in a message, including the bytes of a text or data value.
+pub(crate) const MAX_LIST_LENGTH: u32 = (1 << 29) - 1;
+
+/// The largest size hint that is trusted without limits on lists.
+const UNLIMITED_HINT: usize = 4096;
+
+#[derive(Clone, Copy, Default)]
+struct Budget {
+    max_depth: Option<u32>,
+    max_list_length: Option<u32>,
+    max_blob_size: Option<u32>,
+    max_message_words: Option<u64>,
+    /// The number of structs and lists that enclose the current value.
+    depth: u32,
+    /// The words allocated in the message so far.
+    words: u64,
+}
+
+thread_local! {
+    static BUDGET: Cell<Budget> = Cell::default();
+}
+
+/// The budget of a deserialization, until it's dropped. The budget that was there before, of an
+/// enclosing deserialization or none, is restored then.
+pub(crate) struct Session(Budget);
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        BUDGET.set(self.0);
+    }
+}
+
+/// Starts a deserialization with the limits of the options.
+pub(crate) fn start(options: &Options) -> Session {
+    Session(BUDGET.replace(Budget {
+        max_depth: options.max_depth,
+        max_list_length: options.max_list_length,
+        max_blob_size: options.max_blob_size,
+        max_message_words: options.max_message_words,
+        ..Budget::default()
+    }))
+}
+
+/// A struct or list that is being deserialized, until it's dropped.
+pub(crate) struct Nested(());
+
+impl Drop for Nested {
+    fn drop(&mut self) {
+        let mut budget = BUDGET.get();
+        budget.depth -= 1;
+        BUDGET.set(budget);
+    }
+}
+
+/// Enters a struct or list, failing if it's nested too deeply.
+pub(crate) fn enter<E: Error>() -> Result<Nested, E> {
+    let mut budget = BUDGET.get();
+    if let Some(max) = budget.max_depth
+        && budget.depth >= max
+    {
+        return Err(E::custom(format!(
+            "the nesting depth exceeds the limit of {max}"
+        )));
+    }
+    budget.depth += 1;
+    BUDGET.set(budget);
+    Ok(Nested(()))
+}
+
+/// Checks the number of elements of a list, which may not be complete yet.
+pub(crate) fn check_list_length<E: Error>(len: usize) -> Result<(), E> {
+    match BUDGET.get().max_list_length {
+        Some(max) if len > max as usize => Err(E::custom(format!(
+            "the list length of {len} exceeds the limit of {max}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The size of a list to allocate for a size hint, which is only trusted if the list would be
+/// within the limits. Otherwise, the list is allocated again once the actual size is known.
+/// Without limits on lists, only small hints are trusted, as any input may announce a huge one.
+pub(crate) fn list_hint(element_ty: Type, hint: usize) -> usize {
+    let budget = BUDGET.get();
+    if budget.max_list_length.is_none() && budget.max_message_words.is_none() {
+        return hint.min(UNLIMITED_HINT);
+    }
+    let within_length = hint <= MAX_LIST_LENGTH as usize
+        && budget
+            .max_list_length
+            .is_none_or(|max| hint <= max as usize);
+    let within_words = budget.max_message_words.is_none_or(|max| {
+        u32::try_from(hint)
+            .ok()
+            .and_then(|len| list_words(element_ty, len).ok())
+            .is_some_and(|words| budget.words + words <= max)
+    });
+    if within_length && within_words {
+        hint
+    } else {
+        0
+    }
+}
+
+/// Accounts for a list that is about to be allocated.
+pub(crate) fn allocate_list<E: Error>(element_ty: Type, len: usize) -> Result<u32, E> {
+    check_list_length(len)?;
+    let len = u32::try_from(len)
+        .ok()
+        .filter(|&len| len <= MAX_LIST_LENGTH)
+        .ok_or_else(|| E::custom(format!("the list length of {len} is too large")))?;
+    allocate(list_words(element_ty, len).map_err(de_capnp)?)?;
+    Ok(len)
+}
+
+/// Accounts for a struct that is about to be allocated.
+pub(crate) fn allocate_struct<E: Error>(schema: StructSchema) -> Result<(), E> {
+    allocate(struct_words(schema).map_err(de_capnp)?)
+}
+
+/// Checks the size of a text or data value, which may not be complete yet.
+pub(crate) fn check_blob_size<E: Error>(len: usize, text: bool) -> Result<(), E> {
+    let kind = if text { "text" } else { "data" };
+    match BUDGET.get().max_blob_size {
+        Some(max) if len > max as usize => Err(E::custom(format!(
+            "the {kind} size of {len} bytes exceeds the limit of {max}"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the size of a text or data value and accounts for it.
+pub(crate) fn allocate_blob<E: Error>(len: usize, text: bool) -> Result<(), E> {
+    check_blob_size(len, text)?;
+    if len + usize::from(text) > MAX_LIST_LENGTH as usize {
+        let kind = if text { "text" } else { "data" };
+        return Err(E::custom(format!(
+            "the {kind} size of {len} bytes is too large"
+        )));
+    }
+    // Text is NUL terminated
+    allocate((len as u64 + u64::from(text)).div_ceil(8))
+}
+
+fn allocate<E: Error>(words: u64) -> Result<(), E> {
+    let mut budget = BUDGET.get();
+    budget.words = budget.words.saturating_add(words);
+    if let Some(max) = budget.max_message_words
+        && budget.words > max
+    {
+        return Err(E::custom(format!(
+            "the message exceeds the limit of {max} words"
+        )));
+    }
+    BUDGET.set(budget);
+    Ok(())
+}
+
+fn struct_words(schema: StructSchema) -> capnp::Result<u64> {
+    let capnp::schema_capnp::node::Struct(node) = schema.get_proto().which()? else {
+        return Err(capnp::Error::failed("Not a struct".to_owned()));
+    };
+    Ok(u64::from(node.get_data_word_count()) + u64::from(node.get_pointer_count()))
+}
+
+/// The words of a list without the values its pointers point to.
+fn list_words(element_ty: Type, len: u32) -> capnp::Result<u64> {
+    let len = u64::from(len);
+    let bits = match element_ty.which() {
+        TypeVariant::Void => 0,
+        TypeVariant::Bool => 1,
+        TypeVariant::Int8 | TypeVariant::UInt8 => 8,
+        TypeVariant::Int16 | TypeVariant::UInt16 | TypeVariant::Enum(_) => 16,
+        TypeVariant::Int32 | TypeVariant::UInt32 | TypeVariant::Float32 => 32,
+        TypeVariant::Int64 | TypeVariant::UInt64 | TypeVariant::Float64 => 64,
+        TypeVariant::Text
+        | TypeVariant::Data
+        | TypeVariant::List(_)
+        | TypeVariant::AnyPointer
+        | TypeVariant::Capability => 64,
+        // Struct lists start with a tag word
+        TypeVariant::Struct(raw) => return Ok(1 + len * struct_words(raw.into())?),
+    };
+    Ok((len * bits).div_ceil(64))
+}
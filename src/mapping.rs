@@ -0,0 +1,320 @@
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    sync::LazyLock,
+};
+
+use capnp::{
+    introspect::TypeVariant,
+    schema::{Field, StructSchema},
+};
+use once_map::OnceMap;
+
+use crate::{
+    Options, UnionRepresentation,
+    annotations::{
+        discriminator, field_key, flatten_prefix, is_required, requires_all, requires_member,
+    },
+    naming::{Keys, Naming},
+    schema::{display_name, field_name, has_union, is_group, is_union_member},
+};
+
+/// The keys of the serialized map of a struct and the fields they belong to.
+///
+/// Without annotations, every field has its own key. Flattened groups and structs contribute their
+/// fields to the map of the containing struct, so a field may be nested below a path of group and
+/// struct fields.
+pub(crate) struct StructMapping {
+    /// The unions of the struct and its flattened groups and structs.
+    pub(crate) unions: Vec<UnionScope>,
+    /// The map entries in serialization order.
+    pub(crate) entries: Vec<Entry>,
+    pub(crate) naming: Naming,
+    keys: Keys<Route>,
+    /// The ID of the mapped struct.
+    id: u64,
+    /// The interned keys of the fields, once they're needed.
+    field_names: OnceCell<&'static [&'static str]>,
+}
+
+/// A union, either of the mapped struct itself or of a flattened group or struct.
+pub(crate) struct UnionScope {
+    /// The fields leading to the struct or group containing the union.
+    pub(crate) path: Box<[Field]>,
+    pub(crate) schema: StructSchema,
+    /// The key of the discriminator, if the union is tagged.
+    pub(crate) tag: Option<String>,
+    /// The key holding the value of the active member, if the union is adjacently tagged.
+    pub(crate) content: Option<String>,
+    /// Whether a member has to be given on input, by `$requireMember`.
+    pub(crate) required: bool,
+}
+
+pub(crate) enum Entry {
+    /// The discriminator of a tagged union.
+    Tag { key: String, union: usize },
+    /// A field below the given path.
+    Field(Box<FieldEntry>),
+}
+
+/// A field of the serialized map.
+pub(crate) struct FieldEntry {
+    pub(crate) key: String,
+    pub(crate) path: Box<[Field]>,
+    pub(crate) field: Field,
+    /// The union the field is a member of.
+    pub(crate) union: Option<usize>,
+    /// Whether the field has to be present on input, by `$required` or `$requireAll`.
+    pub(crate) required: bool,
+}
+
+/// The field names of structs, as hints for `deserialize_struct`, which requires them to be
+/// `'static`. As the names depend on the options, they're interned by their content, joined by NUL.
+static FIELD_NAMES: LazyLock<OnceMap<String, Box<[&'static str]>>> = LazyLock::new(OnceMap::new);
+
+/// Mappings by struct ID and whether the struct is a group.
+type Mappings = HashMap<(u64, bool), Rc<StructMapping>>;
+
+thread_local! {
+    /// The mappings of the running conversion, or `None` outside of a conversion. A conversion
+    /// uses the same options throughout, so a mapping can be shared by all values of its type.
+    static MAPPINGS: RefCell<Option<Mappings>> = const { RefCell::new(None) };
+}
+
+/// The cached mappings of a conversion, until it's dropped. Any enclosing conversion gets its own
+/// mappings back then.
+pub(crate) struct Cache(Option<Mappings>);
+
+/// Starts caching the mappings of a conversion with one set of options.
+pub(crate) fn cache() -> Cache {
+    Cache(MAPPINGS.replace(Some(HashMap::new())))
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        MAPPINGS.set(self.0.take());
+    }
+}
+
+/// What a key of the serialized map refers to.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Route {
+    /// The discriminator of the union with the given index.
+    Tag(usize),
+    /// The value of the selected member of the union with the given index.
+    Content(usize),
+    /// The entry with the given index.
+    Field(usize),
+}
+
+impl StructMapping {
+    /// Maps a struct, or a group given the group field.
+    pub(crate) fn new(
+        schema: StructSchema,
+        group: Option<Field>,
+        options: &Options,
+    ) -> capnp::Result<Self> {
+        let naming = Naming::new(options);
+        let mut mapping = Self {
+            unions: Vec::new(),
+            entries: Vec::new(),
+            naming,
+            keys: Keys::new(naming),
+            id: schema.get_proto().get_id(),
+            field_names: OnceCell::new(),
+        };
+        mapping.add(schema, group, &mut Vec::new(), "", options)?;
+        Ok(mapping)
+    }
+
+    /// Maps a struct or group like [`StructMapping::new`], reusing the mapping of an earlier value
+    /// of the same type within the conversion.
+    pub(crate) fn get(
+        schema: StructSchema,
+        group: Option<Field>,
+        options: &Options,
+    ) -> capnp::Result<Rc<Self>> {
+        let proto = schema.get_proto();
+        // The fields of generic structs depend on the type arguments, which the ID doesn't cover
+        let key = (!proto.get_is_generic()).then(|| (proto.get_id(), group.is_some()));
+        if let Some(key) = key
+            && let Some(mapping) =
+                MAPPINGS.with_borrow(|mappings| mappings.as_ref()?.get(&key).cloned())
+        {
+            return Ok(mapping);
+        }
+        let mapping = Rc::new(Self::new(schema, group, options)?);
+        if let Some(key) = key {
+            MAPPINGS.with_borrow_mut(|mappings| {
+                if let Some(mappings) = mappings {
+                    mappings.insert(key, mapping.clone());
+                }
+            });
+        }
+        Ok(mapping)
+    }
+
+    pub(crate) fn route(&self, key: &str) -> Option<Route> {
+        self.keys.get(key)
+    }
+
+    /// Finds the member of a union named by a discriminator value.
+    pub(crate) fn member(&self, union: usize, name: &str) -> capnp::Result<Option<Field>> {
+        let mut candidates = Vec::new();
+        for field in self.unions[union].schema.get_union_fields()? {
+            candidates.push((
+                field,
+                field_key(field, self.naming.convention)?,
+                field_name(field)?,
+            ));
+        }
+        Ok(self.naming.find(name, candidates))
+    }
+
+    /// The keys of all fields, without discriminators.
+    pub(crate) fn field_keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().filter_map(|entry| match entry {
+            Entry::Field(entry) => Some(entry.key.as_str()),
+            Entry::Tag { .. } => None,
+        })
+    }
+
+    /// The keys of all fields like [`StructMapping::field_keys`], as `'static` hints for
+    /// `deserialize_struct`.
+    pub(crate) fn field_names(&self) -> &'static [&'static str] {
+        self.field_names.get_or_init(|| {
+            let joined = self.field_keys().collect::<Vec<_>>().join("\0");
+            FIELD_NAMES.insert(joined, |_| {
+                self.field_keys()
+                    .map(|key| &*key.to_owned().leak())
+                    .collect()
+            })
+        })
+    }
+
+    /// Adds the fields of a struct or group, which is the mapped struct itself or flattened into it.
+    fn add(
+        &mut self,
+        schema: StructSchema,
+        group: Option<Field>,
+        path: &mut Vec<Field>,
+        prefix: &str,
+        options: &Options,
+    ) -> capnp::Result<()> {
+        let union = if has_union(schema)? {
+            let (tag, content) = match discriminator(schema, group)? {
+                Some(discriminator) => {
+                    let tag = match (discriminator.name, group) {
+                        (Some(name), _) => name,
+                        // Like the C++ codec, default to the name of the union
+                        (None, Some(group)) => {
+                            self.keys.insert_alias(
+                                &format!("{prefix}{}", field_name(group)?),
+                                Route::Tag(self.unions.len()),
+                            );
+                            field_key(group, self.naming.convention)?
+                        }
+                        (None, None) => options.union_representation.tag().unwrap_or("which"),
+                    };
+                    (Some(tag), discriminator.value_name)
+                }
+                None => match &options.union_representation {
+                    UnionRepresentation::External => (None, None),
+                    UnionRepresentation::Internal { tag } => (Some(tag.as_str()), None),
+                    UnionRepresentation::Adjacent { tag, content } => {
+                        (Some(tag.as_str()), Some(content.as_str()))
+                    }
+                },
+            };
+            let index = self.unions.len();
+            let tag = tag.map(|tag| format!("{prefix}{tag}"));
+            let content = content.map(|content| format!("{prefix}{content}"));
+            if let Some(tag) = &tag {
+                self.insert_key(schema, tag, Route::Tag(index))?;
+                self.entries.push(Entry::Tag {
+                    key: tag.clone(),
+                    union: index,
+                });
+            }
+            if let Some(content) = &content {
+                self.insert_key(schema, content, Route::Content(index))?;
+            }
+            self.unions.push(UnionScope {
+                path: path.as_slice().into(),
+                schema,
+                tag,
+                content,
+                required: requires_member(schema, group)?,
+            });
+            Some(index)
+        } else {
+            None
+        };
+
+        let require_all = requires_all(schema, group)?;
+        for field in schema.get_fields()? {
+            let inner_prefix = match flatten_prefix(field)? {
+                Some(prefix) => Some(prefix),
+                None => options.flatten_prefix(schema, field)?,
+            };
+            if let Some(inner_prefix) = inner_prefix {
+                let TypeVariant::Struct(raw) = field.get_type().which() else {
+                    return Err(capnp::Error::failed(format!(
+                        "only groups and structs can be flattened, `{}` can't",
+                        field_key(field, self.naming.convention)?
+                    )));
+                };
+                let inner = StructSchema::new(raw);
+                let id = inner.get_proto().get_id();
+                if self.id == id
+                    || path.iter().any(|field| match field.get_type().which() {
+                        TypeVariant::Struct(raw) => {
+                            StructSchema::new(raw).get_proto().get_id() == id
+                        }
+                        _ => false,
+                    })
+                {
+                    return Err(capnp::Error::failed(format!(
+                        "`{}` can't be flattened into itself",
+                        field_key(field, self.naming.convention)?
+                    )));
+                }
+                path.push(field);
+                self.add(
+                    inner,
+                    is_group(field).then_some(field),
+                    path,
+                    &format!("{prefix}{inner_prefix}"),
+                    options,
+                )?;
+                path.pop();
+                continue;
+            }
+            let key = format!("{prefix}{}", field_key(field, self.naming.convention)?);
+            let route = Route::Field(self.entries.len());
+            self.insert_key(schema, &key, route)?;
+            self.keys
+                .insert_alias(&format!("{prefix}{}", field_name(field)?), route);
+            self.entries.push(Entry::Field(Box::new(FieldEntry {
+                key,
+                path: path.as_slice().into(),
+                field,
+                union: union.filter(|_| is_union_member(field)),
+                required: is_required(field)? || (require_all && !is_union_member(field)),
+            })));
+        }
+        Ok(())
+    }
+
+    fn insert_key(&mut self, schema: StructSchema, key: &str, route: Route) -> capnp::Result<()> {
+        if self.keys.insert(key, route) {
+            Ok(())
+        } else {
+            Err(capnp::Error::failed(format!(
+                "the key `{key}` is used more than once in `{}`",
+                display_name(schema)?
+            )))
+        }
+    }
+}
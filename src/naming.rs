@@ -0,0 +1,146 @@
+//! Conversion of field and enumerant names by [`NamingConvention`] and matching of names on input.
+
+use std::{collections::HashMap, sync::LazyLock};
+
+use heck::{ToKebabCase, ToLowerCamelCase, ToShoutySnakeCase, ToSnakeCase, ToUpperCamelCase};
+use once_map::OnceMap;
+
+use crate::{NamingConvention, Options};
+
+/// Converted names, which are derived from the schema and therefore live just as long.
+static CONVERTED: LazyLock<OnceMap<(NamingConvention, &'static str), Box<str>>> =
+    LazyLock::new(OnceMap::new);
+
+impl NamingConvention {
+    /// Converts a name declared in the schema.
+    pub(crate) fn convert(self, name: &'static str) -> &'static str {
+        let convert: fn(&str) -> String = match self {
+            Self::Schema => return name,
+            Self::CamelCase => |name| name.to_lower_camel_case(),
+            Self::PascalCase => |name| name.to_upper_camel_case(),
+            Self::SnakeCase => |name| name.to_snake_case(),
+            Self::ScreamingSnakeCase => |name| name.to_shouty_snake_case(),
+            Self::KebabCase => |name| name.to_kebab_case(),
+        };
+        CONVERTED.insert((self, name), |_| convert(name).into_boxed_str())
+    }
+}
+
+/// The naming options, which decide which names refer to a field or enumerant on input.
+#[derive(Clone, Copy)]
+pub(crate) struct Naming {
+    pub(crate) convention: NamingConvention,
+    schema_names: bool,
+    case_insensitive: bool,
+}
+
+impl Naming {
+    pub(crate) fn new(options: &Options) -> Self {
+        Self {
+            convention: options.naming_convention,
+            schema_names: options.accept_schema_names,
+            case_insensitive: options.case_insensitive_names,
+        }
+    }
+
+    /// Finds the candidate an input name refers to. Each candidate comes with its name on output and
+    /// its name in the schema.
+    ///
+    /// Exact matches of the output name take precedence over the schema name, which takes precedence
+    /// over case-insensitive matches. Case-insensitive matches have to be unique.
+    pub(crate) fn find<T>(
+        self,
+        input: &str,
+        candidates: impl IntoIterator<Item = (T, &'static str, &'static str)>,
+    ) -> Option<T> {
+        let mut schema_match = None;
+        let mut folded_matches = Vec::new();
+        for (candidate, name, schema_name) in candidates {
+            if input == name {
+                return Some(candidate);
+            } else if self.schema_names && input == schema_name {
+                schema_match.get_or_insert(candidate);
+            } else if self.case_insensitive
+                && (input.eq_ignore_ascii_case(name)
+                    || self.schema_names && input.eq_ignore_ascii_case(schema_name))
+            {
+                folded_matches.push(candidate);
+            }
+        }
+        schema_match.or_else(|| unique(folded_matches))
+    }
+}
+
+/// Lookup of map keys, which may have aliases and may be matched case-insensitively.
+pub(crate) struct Keys<V> {
+    keys: HashMap<String, V>,
+    /// The keys derived from the names in the schema, if they're accepted.
+    aliases: Option<HashMap<String, V>>,
+    /// Lowercase keys and aliases if case-insensitive matching is enabled. Keys that fold to the
+    /// same lowercase key are ambiguous and map to `None`.
+    folded: Option<HashMap<String, Option<V>>>,
+}
+
+impl<V: Copy + PartialEq> Keys<V> {
+    pub(crate) fn new(naming: Naming) -> Self {
+        Self {
+            keys: HashMap::new(),
+            aliases: naming.schema_names.then(HashMap::new),
+            folded: naming.case_insensitive.then(HashMap::new),
+        }
+    }
+
+    /// Adds a key, or returns `false` if the key exists already.
+    pub(crate) fn insert(&mut self, key: &str, value: V) -> bool {
+        if self.keys.contains_key(key) {
+            return false;
+        }
+        self.keys.insert(key.to_owned(), value);
+        self.fold(key, value);
+        true
+    }
+
+    /// Adds the key derived from the name in the schema, unless schema names aren't accepted or the
+    /// alias is a key or alias already.
+    pub(crate) fn insert_alias(&mut self, alias: &str, value: V) {
+        let Some(aliases) = &mut self.aliases else {
+            return;
+        };
+        if !self.keys.contains_key(alias) && !aliases.contains_key(alias) {
+            aliases.insert(alias.to_owned(), value);
+            self.fold(alias, value);
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<V> {
+        let alias = || self.aliases.as_ref()?.get(key);
+        if let Some(value) = self.keys.get(key).or_else(alias) {
+            return Some(*value);
+        }
+        self.folded
+            .as_ref()?
+            .get(&key.to_ascii_lowercase())
+            .copied()?
+    }
+
+    fn fold(&mut self, key: &str, value: V) {
+        if let Some(folded) = &mut self.folded {
+            folded
+                .entry(key.to_ascii_lowercase())
+                .and_modify(|existing| {
+                    if *existing != Some(value) {
+                        *existing = None;
+                    }
+                })
+                .or_insert(Some(value));
+        }
+    }
+}
+
+fn unique<T>(mut candidates: Vec<T>) -> Option<T> {
+    if candidates.len() == 1 {
+        candidates.pop()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+
+use base64::{
+    Engine, alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+};
+use capnp::{
+    introspect::{Introspect, TypeVariant},
+    schema::{Field, StructSchema},
+};
+
+use crate::{
+    TypeRegistry,
+    annotations::type_field,
+    schema::{field_name, is_group, is_named_union},
+};
+
+/// Options controlling how Cap'n Proto values are mapped onto the serde data model.
+///
+/// The same options apply to both directions, so a document serialized by a [`crate::CapnpSerdeReader`]
+/// can be read back by a [`crate::CapnpSerdeBuilder`] configured with identical options.
+///
+/// # Example
+///
+/// ```rust
+/// use capnp_serde::{Options, UnionRepresentation};
+///
+/// let options = Options::default().union_representation(UnionRepresentation::internal());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub(crate) union_representation: UnionRepresentation,
+    pub(crate) emit_defaults: bool,
+    pub(crate) null_unset_pointers: bool,
+    pub(crate) data_encoding: DataEncoding,
+    pub(crate) enum_representation: EnumRepresentation,
+    pub(crate) int64_as_string: bool,
+    pub(crate) non_finite_floats: NonFiniteFloats,
+    pub(crate) naming_convention: NamingConvention,
+    pub(crate) accept_schema_names: bool,
+    pub(crate) case_insensitive_names: bool,
+    pub(crate) flatten_groups: bool,
+    /// Key prefixes of flattened fields by struct ID and field name.
+    pub(crate) flattened_fields: HashMap<u64, HashMap<String, String>>,
+    pub(crate) unknown_fields: UnknownFields,
+    /// The inverse of [`Options::strict`], so that strict is the default.
+    pub(crate) allow_conflicting_keys: bool,
+    pub(crate) lenient: bool,
+    pub(crate) numeric_overflow: NumericOverflow,
+    pub(crate) max_depth: Option<u32>,
+    pub(crate) max_list_length: Option<u32>,
+    pub(crate) max_blob_size: Option<u32>,
+    pub(crate) max_message_words: Option<u64>,
+    pub(crate) list_merge: ListMerge,
+    pub(crate) type_registry: TypeRegistry,
+    /// The fields naming the types of `AnyPointer` fields by struct ID and field name.
+    pub(crate) type_fields: HashMap<u64, HashMap<String, String>>,
+    /// Whether the document is merged into an existing message, which is set by the merge entry
+    /// points rather than by an option.
+    pub(crate) merging: bool,
+}
+
+impl Options {
+    /// Selects how the active member of a union is represented.
+    pub fn union_representation(mut self, representation: UnionRepresentation) -> Self {
+        self.union_representation = representation;
+        self
+    }
+
+    /// Serializes unset pointer fields with their default value from the schema, including an
+    /// active union member that is an unset pointer.
+    ///
+    /// By default, unset pointer fields are left out. With this option, they're written with the
+    /// default value from the schema instead, so the reader doesn't need to know the schema
+    /// defaults. Unset `AnyPointer` and capability fields are still left out. Primitive fields are
+    /// always serialized, as Cap'n Proto doesn't tell a default value apart from an unset one.
+    pub fn emit_defaults(mut self, emit_defaults: bool) -> Self {
+        self.emit_defaults = emit_defaults;
+        self
+    }
+
+    /// Serializes unset pointer fields (structs, lists, text, data) and unset elements of lists of
+    /// lists, text and data as unit, which is `null` in JSON.
+    ///
+    /// This takes precedence over [`Options::emit_defaults`] and allows telling an unset list apart from
+    /// an empty one. Deserialization always accepts unit for pointer fields and pointer list elements
+    /// and leaves them unset.
+    pub fn null_unset_pointers(mut self, null_unset_pointers: bool) -> Self {
+        self.null_unset_pointers = null_unset_pointers;
+        self
+    }
+
+    /// Selects how Data values are represented. Deserialization expects the same representation, but
+    /// human-readable formats may also use an array of bytes.
+    pub fn data_encoding(mut self, encoding: DataEncoding) -> Self {
+        self.data_encoding = encoding;
+        self
+    }
+
+    /// Selects whether enums are written by name or by ordinal. Deserialization accepts both, so the
+    /// format has to be self-describing.
+    pub fn enum_representation(mut self, representation: EnumRepresentation) -> Self {
+        self.enum_representation = representation;
+        self
+    }
+
+    /// Serializes `Int64` and `UInt64` values as decimal strings, like the protobuf JSON mapping.
+    ///
+    /// Formats like JSON in JavaScript, TOML and BSON can't represent every 64-bit integer exactly.
+    /// Deserialization accepts decimal strings for integers of any width either way, but formats
+    /// that aren't human readable only read them for 64-bit integers with this option, and then
+    /// expect strings only.
+    pub fn int64_as_string(mut self, int64_as_string: bool) -> Self {
+        self.int64_as_string = int64_as_string;
+        self
+    }
+
+    /// Selects how NaN and infinite floats are represented.
+    pub fn non_finite_floats(mut self, policy: NonFiniteFloats) -> Self {
+        self.non_finite_floats = policy;
+        self
+    }
+
+    /// Converts the names of fields, union members and enumerants. Names given with `$Json.name`
+    /// are kept as they are.
+    pub fn naming_convention(mut self, convention: NamingConvention) -> Self {
+        self.naming_convention = convention;
+        self
+    }
+
+    /// Also accepts the names declared in the schema when deserializing, in addition to the names
+    /// given by the naming convention or `$Json.name`.
+    pub fn accept_schema_names(mut self, accept_schema_names: bool) -> Self {
+        self.accept_schema_names = accept_schema_names;
+        self
+    }
+
+    /// Accepts names that only differ in ASCII case when deserializing. Exact matches take
+    /// precedence, and a name matching several fields or enumerants is rejected.
+    pub fn case_insensitive_names(mut self, case_insensitive_names: bool) -> Self {
+        self.case_insensitive_names = case_insensitive_names;
+        self
+    }
+
+    /// Writes the fields of groups into the map of the containing struct, as if they were declared
+    /// there. Named unions (`name :union { ... }`) stay nested, as their discriminator would
+    /// collide with the one of the containing struct.
+    ///
+    /// Deserialization expects the same layout and fails if two fields end up with the same key.
+    pub fn flatten_groups(mut self, flatten_groups: bool) -> Self {
+        self.flatten_groups = flatten_groups;
+        self
+    }
+
+    /// Writes the fields of a group or struct field into the map of the containing struct `T`,
+    /// with the given prefix prepended to their keys. This works like `$Json.flatten` from
+    /// `json.capnp`, which takes precedence.
+    ///
+    /// `T` is the generated type of the containing struct or group, e.g. `complex::Owned`, and
+    /// `field` is the name of the field as declared in the schema. Other types are ignored.
+    pub fn flatten_field<T: Introspect>(mut self, field: &str, prefix: &str) -> Self {
+        if let TypeVariant::Struct(raw) = T::introspect().which() {
+            self.flattened_fields
+                .entry(StructSchema::new(raw).get_proto().get_id())
+                .or_default()
+                .insert(field.to_owned(), prefix.to_owned());
+        }
+        self
+    }
+
+    /// Selects what deserialization does with keys that don't belong to any field, e.g. because
+    /// the document was written with a newer version of the schema.
+    pub fn unknown_fields(mut self, policy: UnknownFields) -> Self {
+        self.unknown_fields = policy;
+        self
+    }
+
+    /// Rejects maps that set the same field twice, or more than one member of a union, when
+    /// deserializing. The error names the struct and both keys.
+    ///
+    /// This is on by default. Otherwise, the value that comes last wins, except that the
+    /// discriminator and the value of a tagged union still have to agree.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.allow_conflicting_keys = !strict;
+        self
+    }
+
+    /// Coerces loosely typed values into the type of the schema when deserializing, which needs a
+    /// self-describing format.
+    ///
+    /// Numbers may be given as decimal strings, also with a fraction of zero for integers, `"true"`,
+    /// `"false"`, `1` and `0` for booleans, numbers and booleans for text, and a single value for a
+    /// list of one element. Anything else, like `"3.5"` for an integer or `"yes"` for a boolean, is
+    /// still rejected.
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    /// Selects what deserialization does with numbers that don't fit into the type of the schema,
+    /// like 70000 for a `UInt16`.
+    ///
+    /// This doesn't apply to floats with a fractional part, which are never accepted for integers.
+    pub fn numeric_overflow(mut self, policy: NumericOverflow) -> Self {
+        self.numeric_overflow = policy;
+        self
+    }
+
+    /// Limits how deeply structs and lists may be nested when deserializing. The root counts as
+    /// the first level, groups count unless they're flattened. There's no limit by default.
+    ///
+    /// Untrusted input may be nested deeply enough to overflow the stack otherwise.
+    pub fn max_depth(mut self, max_depth: Option<u32>) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Limits the number of elements of every list when deserializing. There's no limit by
+    /// default.
+    ///
+    /// Size hints beyond the limit aren't trusted for the allocation, and a sequence fails as soon
+    /// as it has more elements.
+    pub fn max_list_length(mut self, max_list_length: Option<u32>) -> Self {
+        self.max_list_length = max_list_length;
+        self
+    }
+
+    /// Limits the size in bytes of every Text and Data value when deserializing. There's no limit
+    /// by default.
+    pub fn max_blob_size(mut self, max_blob_size: Option<u32>) -> Self {
+        self.max_blob_size = max_blob_size;
+        self
+    }
+
+    /// Limits the size of the message in 8-byte words when deserializing, counting every struct,
+    /// list, text and data value before it's allocated. There's no limit by default.
+    ///
+    /// Lists that are allocated again because of a wrong size hint count twice, as the first
+    /// allocation stays in the message.
+    pub fn max_message_words(mut self, max_message_words: Option<u64>) -> Self {
+        self.max_message_words = max_message_words;
+        self
+    }
+
+    /// Selects what happens to the lists of an existing message that a document is merged into
+    /// with [`crate::CapnpSerdeBuilder::merge_with_options`] or [`crate::merge_into`].
+    pub fn list_merge(mut self, policy: ListMerge) -> Self {
+        self.list_merge = policy;
+        self
+    }
+
+    /// Sets the struct types that `AnyPointer` fields may hold.
+    pub fn type_registry(mut self, registry: TypeRegistry) -> Self {
+        self.type_registry = registry;
+        self
+    }
+
+    /// Reads and writes the `AnyPointer` field of `T` as the struct type whose schema ID is the
+    /// value of `type_field`, a `UInt64` field of the same struct or group. This works like the
+    /// `$Serde.typeField` annotation from `capnp-serde.capnp`, which takes precedence.
+    ///
+    /// `T` is the generated type of the containing struct or group, e.g. `envelope::Owned`, and the
+    /// fields are named as declared in the schema. The struct types have to be registered with
+    /// [`Options::type_registry`]. Other types are ignored.
+    pub fn any_pointer_type<T: Introspect>(mut self, field: &str, type_field: &str) -> Self {
+        if let TypeVariant::Struct(raw) = T::introspect().which() {
+            self.type_fields
+                .entry(StructSchema::new(raw).get_proto().get_id())
+                .or_default()
+                .insert(field.to_owned(), type_field.to_owned());
+        }
+        self
+    }
+
+    /// The field holding the type of the given `AnyPointer` field of the struct or group, if there
+    /// is one.
+    pub(crate) fn type_field(
+        &self,
+        schema: StructSchema,
+        field: Field,
+    ) -> capnp::Result<Option<Field>> {
+        let name = match type_field(field)? {
+            Some(name) => name,
+            None => match self
+                .type_fields
+                .get(&schema.get_proto().get_id())
+                .and_then(|fields| fields.get(field_name(field).ok()?))
+            {
+                Some(name) => name,
+                None => return Ok(None),
+            },
+        };
+        match schema.find_field_by_name(name)? {
+            Some(type_field) if matches!(type_field.get_type().which(), TypeVariant::UInt64) => {
+                Ok(Some(type_field))
+            }
+            Some(_) => Err(capnp::Error::failed(format!(
+                "the type of `{}` has to be given by a UInt64 field, not `{name}`",
+                field_name(field)?
+            ))),
+            None => Err(capnp::Error::failed(format!(
+                "`{name}`, which gives the type of `{}`, isn't a field of the same struct or group",
+                field_name(field)?
+            ))),
+        }
+    }
+
+    /// The key prefix if the given field of the struct or group is flattened by the options.
+    pub(crate) fn flatten_prefix(
+        &self,
+        schema: StructSchema,
+        field: Field,
+    ) -> capnp::Result<Option<&str>> {
+        if let Some(fields) = self.flattened_fields.get(&schema.get_proto().get_id())
+            && let Some(prefix) = fields.get(field_name(field)?)
+        {
+            return Ok(Some(prefix));
+        }
+        Ok((self.flatten_groups && is_group(field) && !is_named_union(field)?).then_some(""))
+    }
+}
+
+/// The representation of Cap'n Proto unions (named or anonymous) in the serialized map.
+///
+/// The naming follows [serde's enum representations](https://serde.rs/enum-representations.html).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum UnionRepresentation {
+    /// The active member is written like a regular field, `{"d": 84}`.
+    ///
+    /// Void members are written with a unit value (`null` in JSON).
+    #[default]
+    External,
+    /// A discriminator field names the active member, which is written like a regular field,
+    /// `{"which": "d", "d": 84}`. This matches the JSON codec shipped with Cap'n Proto for C++.
+    ///
+    /// Void members are only represented by the discriminator.
+    Internal {
+        /// The key of the discriminator field.
+        tag: String,
+    },
+    /// A discriminator field names the active member and a second field holds its value,
+    /// `{"type": "d", "value": 84}`.
+    ///
+    /// Void members are only represented by the discriminator. When deserializing, the
+    /// discriminator has to appear before the value.
+    Adjacent {
+        /// The key of the discriminator field.
+        tag: String,
+        /// The key of the field holding the value of the active member.
+        content: String,
+    },
+}
+
+impl UnionRepresentation {
+    /// Internal tagging with the discriminator `"which"`.
+    pub fn internal() -> Self {
+        Self::Internal {
+            tag: "which".to_owned(),
+        }
+    }
+
+    /// Adjacent tagging with the keys `"type"` and `"value"`.
+    pub fn adjacent() -> Self {
+        Self::Adjacent {
+            tag: "type".to_owned(),
+            content: "value".to_owned(),
+        }
+    }
+
+    /// The key of the discriminator field, if there is one.
+    pub(crate) fn tag(&self) -> Option<&str> {
+        match self {
+            Self::External => None,
+            Self::Internal { tag } | Self::Adjacent { tag, .. } => Some(tag),
+        }
+    }
+}
+
+/// The representation of enum values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnumRepresentation {
+    /// The name of the enumerant, `"red"`.
+    #[default]
+    Name,
+    /// The ordinal of the enumerant as a `u16`, `0`.
+    Ordinal,
+    /// The name for human-readable formats like JSON and YAML, the ordinal for compact formats like
+    /// MessagePack and CBOR.
+    NameIfHumanReadable,
+}
+
+impl EnumRepresentation {
+    /// Whether enums are written by ordinal for the format.
+    pub(crate) fn use_ordinal(self, human_readable: bool) -> bool {
+        match self {
+            Self::Name => false,
+            Self::Ordinal => true,
+            Self::NameIfHumanReadable => !human_readable,
+        }
+    }
+}
+
+/// The representation of NaN and infinite `Float32` and `Float64` values.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFiniteFloats {
+    /// Passes the value to the format like any other float. JSON writes `null`, which can't be read
+    /// back.
+    #[default]
+    Native,
+    /// The strings `"NaN"`, `"Infinity"` and `"-Infinity"`, like the protobuf JSON mapping.
+    /// Deserialization accepts them with any policy, but formats that aren't human readable only
+    /// look for them with this one.
+    Strings,
+    /// Fails the serialization.
+    Error,
+}
+
+impl NonFiniteFloats {
+    /// The string for a NaN or infinite value.
+    pub(crate) fn encode(value: f64) -> &'static str {
+        if value.is_nan() {
+            "NaN"
+        } else if value.is_sign_positive() {
+            "Infinity"
+        } else {
+            "-Infinity"
+        }
+    }
+
+    /// Parses the string for a NaN or infinite value.
+    pub(crate) fn decode(text: &str) -> Option<f64> {
+        match text {
+            "NaN" => Some(f64::NAN),
+            "Infinity" => Some(f64::INFINITY),
+            "-Infinity" => Some(f64::NEG_INFINITY),
+            _ => None,
+        }
+    }
+}
+
+/// The handling of map keys that don't belong to any field of the struct when deserializing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownFields {
+    /// Fails the deserialization.
+    #[default]
+    Reject,
+    /// Skips the value.
+    Ignore,
+    /// Skips the value, but records the path of the key, which is available from
+    /// [`crate::CapnpSerdeBuilder::unknown_fields`] afterwards.
+    Collect,
+}
+
+/// The handling of numbers that are out of range for their type when deserializing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NumericOverflow {
+    /// Fails the deserialization.
+    #[default]
+    Error,
+    /// Uses the closest value of the type, e.g. 65535 for 70000 as a `UInt16`, or the largest
+    /// finite `Float32` for 1e300.
+    Saturate,
+    /// Keeps the low bits of an integer in two's complement, e.g. 4464 for 70000 as a `UInt16`.
+    /// Floats that don't fit into a `Float32` and integers beyond 128 bits still fail.
+    Wrap,
+}
+
+/// The handling of lists that are already set when merging a document into an existing message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListMerge {
+    /// Replaces the list with the one of the document.
+    #[default]
+    Replace,
+    /// Appends the elements of the document to the elements of the list.
+    Append,
+}
+
+/// The naming convention for fields and enumerants, which are declared in camelCase in schemas.
+///
+/// The naming follows serde's `rename_all` attribute.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum NamingConvention {
+    /// The names as declared in the schema.
+    #[default]
+    Schema,
+    /// `fieldName`
+    CamelCase,
+    /// `FieldName`
+    PascalCase,
+    /// `field_name`
+    SnakeCase,
+    /// `FIELD_NAME`
+    ScreamingSnakeCase,
+    /// `field-name`
+    KebabCase,
+}
+
+/// The representation of Data values.
+///
+/// Fields annotated with `$Json.base64` or `$Json.hex` from `json.capnp` always use that encoding.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DataEncoding {
+    /// Base64 for human-readable formats like JSON and YAML, raw bytes otherwise.
+    #[default]
+    Auto,
+    /// Standard base64 with padding, `"3q2+7w=="`.
+    Base64,
+    /// URL-safe base64 without padding, `"3q2-7w"`.
+    Base64Url,
+    /// Lowercase hexadecimal digits, `"deadbeef"`.
+    Hex,
+    /// Raw bytes via `serialize_bytes`, which most human-readable formats write as an array of numbers.
+    Bytes,
+}
+
+/// Base64 engines that accept input with and without padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+const BASE64_URL: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+impl DataEncoding {
+    /// Replaces [`DataEncoding::Auto`] with the encoding for the format.
+    pub(crate) fn resolve(self, human_readable: bool) -> Self {
+        match self {
+            Self::Auto if human_readable => Self::Base64,
+            Self::Auto => Self::Bytes,
+            encoding => encoding,
+        }
+    }
+
+    /// Encodes data as a string, or returns `None` for the byte encodings.
+    pub(crate) fn encode(self, data: &[u8]) -> Option<String> {
+        match self {
+            Self::Base64 => Some(BASE64.encode(data)),
+            Self::Base64Url => Some(BASE64_URL.encode(data)),
+            Self::Hex => Some(hex::encode(data)),
+            Self::Auto | Self::Bytes => None,
+        }
+    }
+
+    /// Decodes a string, or returns `None` for the byte encodings.
+    pub(crate) fn decode(self, text: &str) -> Option<Result<Vec<u8>, String>> {
+        let result = match self {
+            Self::Base64 => BASE64.decode(text).map_err(|err| err.to_string()),
+            Self::Base64Url => BASE64_URL.decode(text).map_err(|err| err.to_string()),
+            Self::Hex => hex::decode(text).map_err(|err| err.to_string()),
+            Self::Auto | Self::Bytes => return None,
+        };
+        Some(result)
+    }
+}
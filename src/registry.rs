@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use capnp::{
+    any_pointer, dynamic_struct, dynamic_value,
+    introspect::{Type, TypeVariant},
+    schema::StructSchema,
+    traits::Owned,
+};
+
+/// Struct types by schema ID, for reading and writing the values of `AnyPointer` fields.
+///
+/// The field that names the type of an `AnyPointer` field is selected with
+/// [`crate::Options::any_pointer_type`] or the `$Serde.typeField` annotation.
+///
+/// # Example
+///
+/// ```ignore
+/// use capnp_serde::{Options, TypeRegistry};
+///
+/// let registry = TypeRegistry::new().register::<person::Owned>();
+/// let options = Options::default().type_registry(registry);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct TypeRegistry {
+    types: HashMap<u64, Registered>,
+}
+
+/// A registered struct type, with functions to view an `AnyPointer` as the generated type.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Registered {
+    pub(crate) ty: Type,
+    read: for<'a> fn(any_pointer::Reader<'a>) -> capnp::Result<dynamic_value::Reader<'a>>,
+    init: for<'a> fn(any_pointer::Builder<'a>) -> dynamic_value::Builder<'a>,
+}
+
+impl TypeRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the struct type `T`, which is the generated type of the struct, e.g.
+    /// `person::Owned`. Other types are ignored.
+    pub fn register<T>(mut self) -> Self
+    where
+        T: Owned,
+        for<'a> T::Reader<'a>: Into<dynamic_value::Reader<'a>>,
+        for<'a> T::Builder<'a>: Into<dynamic_value::Builder<'a>>,
+    {
+        let ty = T::introspect();
+        if let TypeVariant::Struct(raw) = ty.which() {
+            let registered = Registered {
+                ty,
+                read: read::<T>,
+                init: init::<T>,
+            };
+            self.types
+                .insert(StructSchema::new(raw).get_proto().get_id(), registered);
+        }
+        self
+    }
+
+    /// The schema of the struct type with the given ID, if it's registered.
+    pub fn get(&self, id: u64) -> Option<StructSchema> {
+        self.types
+            .get(&id)
+            .and_then(|registered| match registered.ty.which() {
+                TypeVariant::Struct(raw) => Some(StructSchema::new(raw)),
+                _ => None,
+            })
+    }
+
+    /// The registered struct type with the given ID, or an error naming the field that holds it.
+    pub(crate) fn lookup(&self, id: u64, field: &str) -> capnp::Result<&Registered> {
+        self.types.get(&id).ok_or_else(|| {
+            capnp::Error::failed(format!("the type {id:#018x} of `{field}` isn't registered"))
+        })
+    }
+}
+
+impl Registered {
+    /// Reads an `AnyPointer` as the registered struct.
+    pub(crate) fn read<'a>(
+        &self,
+        reader: any_pointer::Reader<'a>,
+    ) -> capnp::Result<dynamic_value::Reader<'a>> {
+        (self.read)(reader)
+    }
+
+    /// Initializes an `AnyPointer` as the registered struct.
+    pub(crate) fn init<'a>(
+        &self,
+        builder: any_pointer::Builder<'a>,
+    ) -> capnp::Result<dynamic_struct::Builder<'a>> {
+        match (self.init)(builder) {
+            dynamic_value::Builder::Struct(builder) => Ok(builder),
+            _ => Err(capnp::Error::failed("Not a struct".to_owned())),
+        }
+    }
+}
+
+fn read<T>(reader: any_pointer::Reader<'_>) -> capnp::Result<dynamic_value::Reader<'_>>
+where
+    T: Owned,
+    for<'a> T::Reader<'a>: Into<dynamic_value::Reader<'a>>,
+{
+    Ok(reader.get_as::<T::Reader<'_>>()?.into())
+}
+
+fn init<T>(builder: any_pointer::Builder<'_>) -> dynamic_value::Builder<'_>
+where
+    T: Owned,
+    for<'a> T::Builder<'a>: Into<dynamic_value::Builder<'a>>,
+{
+    builder.init_as::<T::Builder<'_>>().into()
+}
@@ -0,0 +1,76 @@
+use capnp::{
+    introspect::{Type, TypeVariant},
+    schema::{EnumSchema, Field, StructSchema},
+    schema_capnp::{field, node},
+};
+
+/// The name of a struct, qualified by the file and any containing scopes.
+pub(crate) fn display_name(schema: StructSchema) -> capnp::Result<&'static str> {
+    Ok(schema.get_proto().get_display_name()?.to_str()?)
+}
+
+/// The name of an enum, qualified by the file and any containing scopes.
+pub(crate) fn enum_display_name(schema: EnumSchema) -> capnp::Result<&'static str> {
+    Ok(schema.get_proto().get_display_name()?.to_str()?)
+}
+
+/// The name of a type as written in a schema, e.g. `List(Int32)`.
+pub(crate) fn type_name(ty: Type) -> String {
+    let name = match ty.which() {
+        TypeVariant::Void => "Void",
+        TypeVariant::Bool => "Bool",
+        TypeVariant::Int8 => "Int8",
+        TypeVariant::Int16 => "Int16",
+        TypeVariant::Int32 => "Int32",
+        TypeVariant::Int64 => "Int64",
+        TypeVariant::UInt8 => "UInt8",
+        TypeVariant::UInt16 => "UInt16",
+        TypeVariant::UInt32 => "UInt32",
+        TypeVariant::UInt64 => "UInt64",
+        TypeVariant::Float32 => "Float32",
+        TypeVariant::Float64 => "Float64",
+        TypeVariant::Text => "Text",
+        TypeVariant::Data => "Data",
+        TypeVariant::AnyPointer => "AnyPointer",
+        TypeVariant::Capability => "Capability",
+        TypeVariant::Struct(raw) => display_name(raw.into()).unwrap_or("struct"),
+        TypeVariant::Enum(raw) => enum_display_name(raw.into()).unwrap_or("enum"),
+        TypeVariant::List(inner) => return format!("List({})", type_name(inner)),
+    };
+    name.to_owned()
+}
+
+/// The name of a field as declared in the schema.
+pub(crate) fn field_name(field: Field) -> capnp::Result<&'static str> {
+    Ok(field.get_proto().get_name()?.to_str()?)
+}
+
+/// Whether the field is a member of the (anonymous) union of its containing struct or group.
+pub(crate) fn is_union_member(field: Field) -> bool {
+    field.get_proto().get_discriminant_value() != field::NO_DISCRIMINANT
+}
+
+/// Whether the field is a group rather than a slot.
+pub(crate) fn is_group(field: Field) -> bool {
+    matches!(field.get_proto().which(), Ok(field::Group(_)))
+}
+
+/// Whether the field is a named union, which is a group that consists of a union only.
+pub(crate) fn is_named_union(field: Field) -> capnp::Result<bool> {
+    if !is_group(field) {
+        return Ok(false);
+    }
+    let TypeVariant::Struct(raw) = field.get_type().which() else {
+        return Ok(false);
+    };
+    let schema = StructSchema::new(raw);
+    Ok(has_union(schema)? && schema.get_non_union_fields()?.is_empty())
+}
+
+/// Whether the struct or group contains an (anonymous) union.
+pub(crate) fn has_union(schema: StructSchema) -> capnp::Result<bool> {
+    let node::Struct(st) = schema.get_proto().which()? else {
+        return Err(capnp::Error::failed("Not a struct".to_owned()));
+    };
+    Ok(st.get_discriminant_count() > 0)
+}
@@ -1,7 +1,22 @@
-use capnp::dynamic_value;
+use capnp::{
+    any_pointer, any_pointer_list, data_list, dynamic_list, dynamic_struct, dynamic_value,
+    introspect::{Introspect, Type, TypeVariant},
+    message,
+    schema::Field,
+    text_list,
+    traits::{IntoInternalListReader as _, Owned},
+};
 use serde::ser::{Error as SerdeError, SerializeMap, SerializeSeq};
 use tracing::trace;
 
+use crate::{
+    Error, NonFiniteFloats, Options,
+    annotations::{data_encoding, enumerant_name, field_key},
+    error::{self, Segment, ser_capnp},
+    mapping::{self, Entry, FieldEntry, StructMapping},
+    schema::{display_name, enum_display_name, field_name, is_group, is_union_member, type_name},
+};
+
 /// A type that can be used to serialize a Cap'n Proto dynamic value into any serde-implementing format.
 ///
 /// This can be used to convert a Cap'n Proto message to any format that implements serde, such as JSON, YAML or CBOR.
@@ -16,8 +31,49 @@ use tracing::trace;
 /// let json = serde_json::to_string(&value).unwrap();
 /// assert_eq!(json, "42");
 /// ```
-#[repr(transparent)]
-pub struct CapnpSerdeReader<'a>(dynamic_value::Reader<'a>);
+pub struct CapnpSerdeReader<'a> {
+    value: dynamic_value::Reader<'a>,
+    options: Options,
+}
+
+impl<'a> CapnpSerdeReader<'a> {
+    /// Creates a `CapnpSerdeReader` for the root of a message of type `O`, given as the
+    /// `AnyPointer` root of the message.
+    ///
+    /// Unlike the value of `get_root`, an unset `Text` or `Data` root is serialized as `null`, which
+    /// [`crate::CapnpSerdeBuilder`] reads back as an unset root.
+    pub fn from_root<O>(root: any_pointer::Reader<'a>) -> capnp::Result<Self>
+    where
+        O: Owned + Introspect,
+        O::Reader<'a>: Into<dynamic_value::Reader<'a>>,
+    {
+        let value = match O::introspect().which() {
+            // Like any unset pointer without a type
+            TypeVariant::Text | TypeVariant::Data if root.is_null() => root.into(),
+            _ => root.get_as::<O::Reader<'a>>()?.into(),
+        };
+        Ok(Self::from(value))
+    }
+
+    /// Replaces the [`Options`] used for serialization.
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Serializes the value like [`serde::Serialize::serialize`], but locates errors within the
+    /// message.
+    pub fn try_serialize<S>(&self, serializer: S) -> Result<S::Ok, Error>
+    where
+        S: serde::Serializer,
+        S::Error: Send + Sync + 'static,
+    {
+        error::capture(
+            || root_type_name(self.value),
+            || serde::Serialize::serialize(self, serializer),
+        )
+    }
+}
 
 impl<'a, R> From<R> for CapnpSerdeReader<'a>
 where
@@ -27,7 +83,10 @@ where
     ///
     /// This is the initializer for `CapnpSerdeReader`.
     fn from(reader: R) -> Self {
-        Self(reader.into())
+        Self {
+            value: reader.into(),
+            options: Options::default(),
+        }
     }
 }
 
@@ -36,78 +95,258 @@ impl serde::ser::Serialize for CapnpSerdeReader<'_> {
     where
         S: serde::Serializer,
     {
-        trace!("CapnpSerdeReader::serialize {:?}", self.0);
-        match self.0 {
+        error::start();
+        let _mappings = mapping::cache();
+        ValueSerializer {
+            value: self.value,
+            field: None,
+            options: &self.options,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// The recursive part of [`CapnpSerdeReader`], borrowing the options from the root.
+struct ValueSerializer<'a, 'o> {
+    value: dynamic_value::Reader<'a>,
+    /// The field holding the value, for field annotations.
+    field: Option<Field>,
+    options: &'o Options,
+}
+
+impl<'a, 'o> ValueSerializer<'a, 'o> {
+    fn nested(&self, value: dynamic_value::Reader<'a>) -> Self {
+        Self {
+            value,
+            field: None,
+            options: self.options,
+        }
+    }
+
+    fn field(&self, value: dynamic_value::Reader<'a>, field: Field) -> Self {
+        Self {
+            value,
+            field: Some(field),
+            options: self.options,
+        }
+    }
+
+    /// The value of a field, with `AnyPointer` values read as the struct type given by the
+    /// options.
+    fn field_value(
+        &self,
+        reader: dynamic_struct::Reader<'a>,
+        field: Field,
+    ) -> capnp::Result<dynamic_value::Reader<'a>> {
+        let value = reader.get(field)?;
+        if let dynamic_value::Reader::AnyPointer(pointer) = value
+            && !pointer.is_null()
+            && let Some(type_field) = self.options.type_field(reader.get_schema(), field)?
+        {
+            let dynamic_value::Reader::UInt64(id) = reader.get(type_field)? else {
+                return Err(capnp::Error::failed("Internal error".to_owned()));
+            };
+            let registered = self.options.type_registry.lookup(id, field_name(field)?)?;
+            return registered.read(pointer);
+        }
+        Ok(value)
+    }
+
+    /// Collects the entries of the map of a struct with their types, so the length is known
+    /// upfront. `None` stands for an unset pointer written as null.
+    ///
+    /// Non-active union fields are always excluded. Unset pointer fields are excluded, written as
+    /// null or written with their default value, depending on the options.
+    fn map_entries<'m>(
+        &self,
+        reader: dynamic_struct::Reader<'a>,
+        mapping: &'m StructMapping,
+    ) -> capnp::Result<Vec<(&'m str, Type, Option<Self>)>> {
+        let mut entries = Vec::with_capacity(mapping.entries.len());
+        for entry in &mapping.entries {
+            match entry {
+                Entry::Tag { key, union } => {
+                    let Some(scope) = descend(reader, &mapping.unions[*union].path)? else {
+                        continue;
+                    };
+                    if let Some(active) = scope.which()? {
+                        let name = field_key(active, self.options.naming_convention)?;
+                        let name = dynamic_value::Reader::Text(name.into());
+                        entries.push((
+                            key.as_str(),
+                            TypeVariant::Text.into(),
+                            Some(self.nested(name)),
+                        ));
+                    }
+                }
+                Entry::Field(entry) => {
+                    let FieldEntry {
+                        key,
+                        path,
+                        field,
+                        union,
+                        ..
+                    } = &**entry;
+                    let Some(scope) = descend(reader, path)? else {
+                        continue;
+                    };
+                    let mut key = key.as_str();
+                    if let Some(union) = union {
+                        let union = &mapping.unions[*union];
+                        if !is_active(scope, *field)? {
+                            continue;
+                        }
+                        // Void members are fully described by the discriminator
+                        if union.tag.is_some()
+                            && matches!(field.get_type().which(), TypeVariant::Void)
+                        {
+                            continue;
+                        }
+                        if let Some(content) = &union.content {
+                            key = content;
+                        }
+                    }
+                    if scope.has(*field)?
+                        || (self.options.emit_defaults
+                            && !self.options.null_unset_pointers
+                            && has_default_value(*field))
+                    {
+                        let value = self.field(self.field_value(scope, *field)?, *field);
+                        entries.push((key, field.get_type(), Some(value)));
+                    } else if self.options.null_unset_pointers {
+                        entries.push((key, field.get_type(), None));
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl serde::ser::Serialize for ValueSerializer<'_, '_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        trace!("CapnpSerdeReader::serialize {:?}", self.value);
+        match self.value {
             dynamic_value::Reader::Void => serializer.serialize_unit(),
             dynamic_value::Reader::Bool(value) => serializer.serialize_bool(value),
             dynamic_value::Reader::Int8(value) => serializer.serialize_i8(value),
             dynamic_value::Reader::Int16(value) => serializer.serialize_i16(value),
             dynamic_value::Reader::Int32(value) => serializer.serialize_i32(value),
+            dynamic_value::Reader::Int64(value) if self.options.int64_as_string => {
+                serializer.collect_str(&value)
+            }
             dynamic_value::Reader::Int64(value) => serializer.serialize_i64(value),
             dynamic_value::Reader::UInt8(value) => serializer.serialize_u8(value),
             dynamic_value::Reader::UInt16(value) => serializer.serialize_u16(value),
             dynamic_value::Reader::UInt32(value) => serializer.serialize_u32(value),
+            dynamic_value::Reader::UInt64(value) if self.options.int64_as_string => {
+                serializer.collect_str(&value)
+            }
             dynamic_value::Reader::UInt64(value) => serializer.serialize_u64(value),
+            dynamic_value::Reader::Float32(value)
+                if !value.is_finite()
+                    && self.options.non_finite_floats != NonFiniteFloats::Native =>
+            {
+                serialize_non_finite(value.into(), self.options.non_finite_floats, serializer)
+            }
+            dynamic_value::Reader::Float64(value)
+                if !value.is_finite()
+                    && self.options.non_finite_floats != NonFiniteFloats::Native =>
+            {
+                serialize_non_finite(value, self.options.non_finite_floats, serializer)
+            }
             dynamic_value::Reader::Float32(value) => serializer.serialize_f32(value),
             dynamic_value::Reader::Float64(value) => serializer.serialize_f64(value),
+            dynamic_value::Reader::Enum(value)
+                if self
+                    .options
+                    .enum_representation
+                    .use_ordinal(serializer.is_human_readable()) =>
+            {
+                serializer.serialize_u16(value.get_value())
+            }
             dynamic_value::Reader::Enum(value) => {
-                if let Some(enumerant) = value.get_enumerant().map_err(SerdeError::custom)? {
+                if let Some(enumerant) = value.get_enumerant().map_err(ser_capnp)? {
                     serializer.serialize_unit_variant(
-                        enumerant
-                            .get_containing_enum()
-                            .get_proto()
-                            .get_display_name()
-                            .map_err(SerdeError::custom)?
-                            .to_str()
-                            .map_err(SerdeError::custom)?,
+                        enum_display_name(enumerant.get_containing_enum()).map_err(ser_capnp)?,
                         enumerant.get_ordinal() as _,
-                        enumerant
-                            .get_proto()
-                            .get_name()
-                            .map_err(SerdeError::custom)?
-                            .to_str()
-                            .map_err(SerdeError::custom)?,
+                        enumerant_name(enumerant, self.options.naming_convention)
+                            .map_err(ser_capnp)?,
                     )
                 } else {
-                    serializer.serialize_unit()
+                    // Written by a newer schema, keep the ordinal so that the value survives a
+                    // round trip
+                    serializer.serialize_u16(value.get_value())
                 }
             }
             dynamic_value::Reader::Text(reader) => {
                 serializer.serialize_str(reader.to_str().map_err(SerdeError::custom)?)
             }
-            dynamic_value::Reader::Data(items) => serializer.serialize_bytes(items),
+            dynamic_value::Reader::Data(items) => {
+                let encoding = self
+                    .field
+                    .map(data_encoding)
+                    .transpose()
+                    .map_err(ser_capnp)?
+                    .flatten()
+                    .unwrap_or(self.options.data_encoding)
+                    .resolve(serializer.is_human_readable());
+                match encoding.encode(items) {
+                    Some(text) => serializer.serialize_str(&text),
+                    None => serializer.serialize_bytes(items),
+                }
+            }
             dynamic_value::Reader::Struct(reader) => {
-                let fields = reader
-                    .get_schema()
-                    .get_fields()
-                    .map_err(SerdeError::custom)?;
-                // This filter excludes fields with their default value set and non-active union fields
-                let fields: Box<[_]> = fields
-                    .into_iter()
-                    .filter(|&field| reader.has(field).unwrap_or_default())
-                    .collect();
-                let mut map = serializer.serialize_map(Some(fields.len() as _))?;
-                for field in fields {
-                    let name = field
-                        .get_proto()
-                        .get_name()
-                        .map_err(SerdeError::custom)?
-                        .to_str()
-                        .map_err(SerdeError::custom)?;
-                    map.serialize_entry(
-                        name,
-                        &Self(reader.get(field).map_err(SerdeError::custom)?),
-                    )?;
+                let mapping = StructMapping::get(
+                    reader.get_schema(),
+                    self.field.filter(|&field| is_group(field)),
+                    self.options,
+                )
+                .map_err(ser_capnp)?;
+                let entries = self.map_entries(reader, &mapping).map_err(ser_capnp)?;
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, ty, value) in entries {
+                    map.serialize_entry(key, &value)
+                        .map_err(|err| error::at(err, Segment::Field(key.to_owned()), ty))?;
                 }
                 map.end()
             }
             dynamic_value::Reader::List(reader) => {
+                let nullable = matches!(
+                    reader.element_type().which(),
+                    TypeVariant::Text | TypeVariant::Data | TypeVariant::List(_)
+                );
+                let nulls = if nullable && self.options.null_unset_pointers {
+                    null_elements(reader).map_err(ser_capnp)?
+                } else {
+                    Vec::new()
+                };
                 let mut sequence = serializer.serialize_seq(Some(reader.len() as _))?;
-                for item in reader.iter() {
-                    sequence.serialize_element(&Self(item.map_err(SerdeError::custom)?))?
+                for (index, item) in reader.iter().enumerate() {
+                    let segment = || Segment::Index(index as u32);
+                    let item = item
+                        .map_err(ser_capnp)
+                        .map_err(|err| error::at(err, segment(), reader.element_type()))?;
+                    let item = self.nested(item);
+                    // Only pointer elements may be null, like the deserializer expects
+                    let result = if !nullable {
+                        sequence.serialize_element(&item)
+                    } else if nulls.get(index) == Some(&true) {
+                        sequence.serialize_element(&None::<ValueSerializer<'_, '_>>)
+                    } else {
+                        sequence.serialize_element(&Some(item))
+                    };
+                    result.map_err(|err| error::at(err, segment(), reader.element_type()))?;
                 }
                 sequence.end()
             }
+            // Without a type for the value, only an unset pointer can be represented
+            dynamic_value::Reader::AnyPointer(reader) if reader.is_null() => {
+                serializer.serialize_unit()
+            }
             dynamic_value::Reader::AnyPointer(_) => {
                 Err(SerdeError::custom("AnyPointer not supported"))
             }
@@ -117,3 +356,130 @@ impl serde::ser::Serialize for CapnpSerdeReader<'_> {
         }
     }
 }
+
+/// Pointer fields with a default value from the schema to emit while they're unset. Primitive
+/// fields can't be unset, so they're always serialized anyway.
+fn has_default_value(field: Field) -> bool {
+    matches!(
+        field.get_type().which(),
+        TypeVariant::Text | TypeVariant::Data | TypeVariant::List(_) | TypeVariant::Struct(_)
+    )
+}
+
+/// Whether the elements of a list are unset pointers, which the dynamic API reads as empty values.
+///
+/// Lists of text and data are read as lists of pointers. Other lists of lists can't be, as their
+/// type isn't known at compile time, so they're copied to look at their pointers if they have an
+/// empty element.
+pub(crate) fn null_elements(list: dynamic_list::Reader<'_>) -> capnp::Result<Vec<bool>> {
+    let pointers = match list.element_type().which() {
+        TypeVariant::Text => {
+            let list: text_list::Reader<'_> = dynamic_value::Reader::from(list).downcast();
+            list.into_internal_list_reader()
+        }
+        TypeVariant::Data => {
+            let list: data_list::Reader<'_> = dynamic_value::Reader::from(list).downcast();
+            list.into_internal_list_reader()
+        }
+        TypeVariant::List(_) => return copied_null_elements(list),
+        _ => return Ok(vec![false; list.len() as usize]),
+    };
+    let pointers = any_pointer_list::Reader { reader: pointers };
+    pointers
+        .iter()
+        .map(|pointer| Ok(pointer?.is_null()))
+        .collect()
+}
+
+fn copied_null_elements(list: dynamic_list::Reader<'_>) -> capnp::Result<Vec<bool>> {
+    let mut empty = false;
+    for element in list.iter() {
+        if let dynamic_value::Reader::List(element) = element? {
+            empty |= element.is_empty();
+        }
+    }
+    if !empty {
+        return Ok(vec![false; list.len() as usize]);
+    }
+    let mut message = message::Builder::new_default();
+    message.set_root::<any_pointer::Owned>(list)?;
+    let pointers: any_pointer_list::Reader<'_> = message.get_root_as_reader()?;
+    pointers
+        .iter()
+        .map(|pointer| Ok(pointer?.is_null()))
+        .collect()
+}
+
+/// Follows the path of flattened groups and structs. Returns `None` if the path passes through an
+/// inactive union member or an unset struct.
+fn descend<'a>(
+    mut reader: dynamic_struct::Reader<'a>,
+    path: &[Field],
+) -> capnp::Result<Option<dynamic_struct::Reader<'a>>> {
+    for &field in path {
+        if is_union_member(field) && !is_active(reader, field)? {
+            return Ok(None);
+        }
+        if !is_group(field) && !reader.has(field)? {
+            return Ok(None);
+        }
+        reader = reader.get(field)?.downcast();
+    }
+    Ok(Some(reader))
+}
+
+fn is_active(reader: dynamic_struct::Reader<'_>, field: Field) -> capnp::Result<bool> {
+    Ok(reader
+        .which()?
+        .is_some_and(|active| active.get_index() == field.get_index()))
+}
+
+/// Serializes NaN or an infinite value with a policy other than [`NonFiniteFloats::Native`].
+fn serialize_non_finite<S>(
+    value: f64,
+    policy: NonFiniteFloats,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let name = NonFiniteFloats::encode(value);
+    if policy == NonFiniteFloats::Strings {
+        serializer.serialize_str(name)
+    } else {
+        Err(S::Error::custom(format!(
+            "the float {name} can't be serialized"
+        )))
+    }
+}
+
+/// The schema type of the root value, as far as the value tells.
+fn root_type_name(value: dynamic_value::Reader<'_>) -> Option<String> {
+    let ty = match value {
+        dynamic_value::Reader::Void => TypeVariant::Void,
+        dynamic_value::Reader::Bool(_) => TypeVariant::Bool,
+        dynamic_value::Reader::Int8(_) => TypeVariant::Int8,
+        dynamic_value::Reader::Int16(_) => TypeVariant::Int16,
+        dynamic_value::Reader::Int32(_) => TypeVariant::Int32,
+        dynamic_value::Reader::Int64(_) => TypeVariant::Int64,
+        dynamic_value::Reader::UInt8(_) => TypeVariant::UInt8,
+        dynamic_value::Reader::UInt16(_) => TypeVariant::UInt16,
+        dynamic_value::Reader::UInt32(_) => TypeVariant::UInt32,
+        dynamic_value::Reader::UInt64(_) => TypeVariant::UInt64,
+        dynamic_value::Reader::Float32(_) => TypeVariant::Float32,
+        dynamic_value::Reader::Float64(_) => TypeVariant::Float64,
+        dynamic_value::Reader::Text(_) => TypeVariant::Text,
+        dynamic_value::Reader::Data(_) => TypeVariant::Data,
+        dynamic_value::Reader::AnyPointer(_) => TypeVariant::AnyPointer,
+        dynamic_value::Reader::Capability(_) => TypeVariant::Capability,
+        dynamic_value::Reader::Enum(value) => {
+            let schema = value.get_enumerant().ok()??.get_containing_enum();
+            return enum_display_name(schema).ok().map(str::to_owned);
+        }
+        dynamic_value::Reader::Struct(reader) => {
+            return display_name(reader.get_schema()).ok().map(str::to_owned);
+        }
+        dynamic_value::Reader::List(reader) => TypeVariant::List(reader.element_type()),
+    };
+    Some(type_name(ty.into()))
+}
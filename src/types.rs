@@ -1,21 +1,17 @@
-use std::sync::LazyLock;
-
 use capnp::{dynamic_value, introspect::TypeVariant};
-use once_map::OnceMap;
 
 pub(crate) mod bools;
+pub(crate) mod content;
 pub(crate) mod data;
 pub(crate) mod enums;
 pub(crate) mod list_element;
+pub(crate) mod nullable;
 pub(crate) mod num;
 pub(crate) mod seq;
 pub(crate) mod structs;
 pub(crate) mod text;
 pub(crate) mod void;
 
-static STRUCT_ENUM_SCHEMA_FIELD_NAMES: LazyLock<OnceMap<u64, Box<[&'static str]>>> =
-    LazyLock::new(OnceMap::new);
-
 fn dynamic_value_type_to_str(value: &dynamic_value::Builder<'_>) -> &'static str {
     match value {
         dynamic_value::Builder::Void => "void",
@@ -63,3 +59,13 @@ fn type_variant_to_str(var: TypeVariant) -> &'static str {
         TypeVariant::List(_) => "list",
     }
 }
+
+/// The error for types that can't be deserialized, either at all or in the given position.
+pub(crate) fn unsupported<E: serde::de::Error>(ty: TypeVariant) -> E {
+    let name = match ty {
+        TypeVariant::AnyPointer => "AnyPointer",
+        TypeVariant::Capability => "Capability",
+        ty => type_variant_to_str(ty),
+    };
+    E::custom(format!("deserializing {name} is not supported here"))
+}
@@ -1,12 +1,37 @@
-use serde::de::Visitor;
+use serde::de::{DeserializeSeed, Unexpected, Visitor};
+use tracing::trace;
 
+use crate::Options;
+
+/// Reads a bool. In lenient mode, `"true"`, `"false"`, `1` and `0` are accepted as well.
 pub(super) struct BoolVisitor<F> {
     setter: F,
+    lenient: bool,
 }
 
 impl<F> BoolVisitor<F> {
-    pub(super) fn new(setter: F) -> Self {
-        Self { setter }
+    pub(super) fn new(options: &Options, setter: F) -> Self {
+        Self {
+            setter,
+            lenient: options.lenient,
+        }
+    }
+}
+
+impl<F, R> BoolVisitor<F>
+where
+    F: FnOnce(bool) -> R,
+{
+    fn coerce<E>(self, value: Option<bool>, unexpected: Unexpected) -> Result<R, E>
+    where
+        E: serde::de::Error,
+    {
+        match value {
+            Some(value) if self.lenient => Ok((self.setter)(value)),
+            Some(_) => Err(E::invalid_type(unexpected, &self)),
+            None if self.lenient => Err(E::invalid_value(unexpected, &self)),
+            None => Err(E::invalid_type(unexpected, &self)),
+        }
     }
 }
 
@@ -17,7 +42,11 @@ where
     type Value = R;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "bool")
+        if self.lenient {
+            write!(formatter, "bool, \"true\", \"false\", 1 or 0")
+        } else {
+            write!(formatter, "bool")
+        }
     }
 
     fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
@@ -26,4 +55,62 @@ where
     {
         Ok((self.setter)(v))
     }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("BoolVisitor::visit_u64 {v:?}");
+        let value = match v {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        self.coerce(value, Unexpected::Unsigned(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("BoolVisitor::visit_i64 {v:?}");
+        let value = match v {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        self.coerce(value, Unexpected::Signed(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("BoolVisitor::visit_str {v:?}");
+        let value = match v {
+            "false" => Some(false),
+            "true" => Some(true),
+            _ => None,
+        };
+        self.coerce(value, Unexpected::Str(v))
+    }
+}
+
+impl<'de, F, R> DeserializeSeed<'de> for BoolVisitor<F>
+where
+    F: FnOnce(bool) -> R,
+{
+    type Value = R;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Other types can only be told apart from bools by self-describing formats
+        if self.lenient {
+            deserializer.deserialize_any(self)
+        } else {
+            deserializer.deserialize_bool(self)
+        }
+    }
 }
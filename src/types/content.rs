@@ -0,0 +1,346 @@
+//! A buffer for values of self-describing formats, which can be deserialized again later.
+//!
+//! Lists of pointers have to be allocated with their size, so the elements of sequences without a
+//! size hint are buffered until the end of the sequence. Replaying them gives nested sequences a
+//! size hint. If a size hint turns out to be wrong, the remaining elements are buffered, so that
+//! the list can be allocated again.
+
+use std::{fmt, marker::PhantomData};
+
+use serde::de::{
+    self, Deserialize, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Unexpected, Visitor,
+};
+
+use crate::limits;
+
+/// A value as the format described it.
+pub(super) enum Content {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    U128(u128),
+    I128(i128),
+    F64(f64),
+    String(String),
+    Bytes(Vec<u8>),
+    Unit,
+    None,
+    Some(Box<Content>),
+    Seq(Vec<Content>),
+    Map(Vec<(Content, Content)>),
+}
+
+impl Content {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            Self::Bool(v) => Unexpected::Bool(*v),
+            Self::U64(v) => Unexpected::Unsigned(*v),
+            Self::I64(v) => Unexpected::Signed(*v),
+            Self::U128(_) => Unexpected::Other("u128"),
+            Self::I128(_) => Unexpected::Other("i128"),
+            Self::F64(v) => Unexpected::Float(*v),
+            Self::String(v) => Unexpected::Str(v),
+            Self::Bytes(v) => Unexpected::Bytes(v),
+            Self::Unit | Self::None => Unexpected::Unit,
+            Self::Some(_) => Unexpected::Option,
+            Self::Seq(_) => Unexpected::Seq,
+            Self::Map(_) => Unexpected::Map,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Content {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ContentVisitor)
+    }
+}
+
+struct ContentVisitor;
+
+impl<'de> Visitor<'de> for ContentVisitor {
+    type Value = Content;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "any value")
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Content::Bool(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Content::U64(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Content::I64(v))
+    }
+
+    fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E> {
+        Ok(Content::U128(v))
+    }
+
+    fn visit_i128<E>(self, v: i128) -> Result<Self::Value, E> {
+        Ok(Content::I128(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Content::F64(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Content::String(v.to_owned()))
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Self::Value, E> {
+        Ok(Content::String(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(Content::Bytes(v.to_owned()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Content::Bytes(v))
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(Content::Unit)
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(Content::None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Content::deserialize(deserializer).map(|content| Content::Some(Box::new(content)))
+    }
+
+    fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Content::deserialize(deserializer)
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let _nested = limits::enter()?;
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(4096));
+        while let Some(element) = seq.next_element()? {
+            elements.push(element);
+            limits::check_list_length(elements.len())?;
+        }
+        Ok(Content::Seq(elements))
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let _nested = limits::enter()?;
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or_default().min(4096));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Content::Map(entries))
+    }
+}
+
+/// Buffers a value along with whether its format is human readable, for replaying it later.
+pub(super) struct Buffer;
+
+impl<'de> DeserializeSeed<'de> for Buffer {
+    type Value = (Content, bool);
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let human_readable = deserializer.is_human_readable();
+        Ok((Content::deserialize(deserializer)?, human_readable))
+    }
+}
+
+/// Replays a buffered value with the error type of the original format.
+pub(super) struct ContentDeserializer<E> {
+    content: Content,
+    /// Whether the original format is human readable, which decides some representations.
+    human_readable: bool,
+    _marker: PhantomData<E>,
+}
+
+impl<E> ContentDeserializer<E> {
+    pub(super) fn new(content: Content, human_readable: bool) -> Self {
+        Self {
+            content,
+            human_readable,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'de, E> Deserializer<'de> for ContentDeserializer<E>
+where
+    E: de::Error,
+{
+    type Error = E;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let human_readable = self.human_readable;
+        match self.content {
+            Content::Bool(v) => visitor.visit_bool(v),
+            Content::U64(v) => visitor.visit_u64(v),
+            Content::I64(v) => visitor.visit_i64(v),
+            Content::U128(v) => visitor.visit_u128(v),
+            Content::I128(v) => visitor.visit_i128(v),
+            Content::F64(v) => visitor.visit_f64(v),
+            Content::String(v) => visitor.visit_string(v),
+            Content::Bytes(v) => visitor.visit_byte_buf(v),
+            Content::Unit => visitor.visit_unit(),
+            Content::None => visitor.visit_none(),
+            // Only options tell values and present optional values apart, like in JSON
+            Content::Some(v) => Self::new(*v, human_readable).deserialize_any(visitor),
+            Content::Seq(v) => {
+                let mut seq = ContentSeq {
+                    elements: v.into_iter(),
+                    human_readable,
+                    _marker: PhantomData,
+                };
+                let value = visitor.visit_seq(&mut seq)?;
+                match seq.elements.len() {
+                    0 => Ok(value),
+                    remaining => Err(E::invalid_length(remaining, &"fewer elements")),
+                }
+            }
+            Content::Map(v) => visitor.visit_map(ContentMap {
+                entries: v.into_iter(),
+                value: None,
+                human_readable,
+                _marker: PhantomData,
+            }),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.content {
+            Content::Unit | Content::None => visitor.visit_none(),
+            Content::Some(v) => visitor.visit_some(Self::new(*v, self.human_readable)),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(E::invalid_type(self.content.unexpected(), &visitor))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        self.human_readable
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ContentSeq<E> {
+    elements: std::vec::IntoIter<Content>,
+    human_readable: bool,
+    _marker: PhantomData<E>,
+}
+
+impl<'de, E> SeqAccess<'de> for ContentSeq<E>
+where
+    E: de::Error,
+{
+    type Error = E;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        self.elements
+            .next()
+            .map(|element| seed.deserialize(ContentDeserializer::new(element, self.human_readable)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elements.len())
+    }
+}
+
+struct ContentMap<E> {
+    entries: std::vec::IntoIter<(Content, Content)>,
+    value: Option<Content>,
+    human_readable: bool,
+    _marker: PhantomData<E>,
+}
+
+impl<'de, E> MapAccess<'de> for ContentMap<E>
+where
+    E: de::Error,
+{
+    type Error = E;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some(value);
+        seed.deserialize(ContentDeserializer::new(key, self.human_readable))
+            .map(Some)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let value = self
+            .value
+            .take()
+            .ok_or_else(|| E::custom("value requested before its key"))?;
+        seed.deserialize(ContentDeserializer::new(value, self.human_readable))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
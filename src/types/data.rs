@@ -1,13 +1,16 @@
-use serde::de::Visitor;
+use serde::de::{DeserializeSeed, SeqAccess, Visitor};
 use tracing::trace;
 
-pub(super) struct DataVisitor<F> {
+use crate::{DataEncoding, limits};
+
+pub(crate) struct DataVisitor<F> {
+    encoding: DataEncoding,
     setter: F,
 }
 
 impl<F> DataVisitor<F> {
-    pub(super) fn new(setter: F) -> Self {
-        Self { setter }
+    pub(crate) fn new(encoding: DataEncoding, setter: F) -> Self {
+        Self { encoding, setter }
     }
 }
 
@@ -18,7 +21,12 @@ where
     type Value = Value;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "text")
+        match self.encoding {
+            DataEncoding::Base64 => write!(formatter, "base64 encoded data"),
+            DataEncoding::Base64Url => write!(formatter, "base64url encoded data"),
+            DataEncoding::Hex => write!(formatter, "hex encoded data"),
+            DataEncoding::Auto | DataEncoding::Bytes => write!(formatter, "data"),
+        }
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
@@ -26,6 +34,58 @@ where
         E: serde::de::Error,
     {
         trace!("DataVisitor::visit_bytes {v:?}");
+        limits::allocate_blob(v.len(), false)?;
         Ok((self.setter)(v))
     }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("DataVisitor::visit_str {v:?}");
+        match self.encoding.decode(v) {
+            Some(bytes) => {
+                let bytes = bytes.map_err(E::custom)?;
+                limits::allocate_blob(bytes.len(), false)?;
+                Ok((self.setter)(&bytes))
+            }
+            None => Err(E::invalid_type(serde::de::Unexpected::Str(v), &self)),
+        }
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        trace!("DataVisitor::visit_seq");
+        // Human-readable formats write raw bytes as an array of numbers
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(4096));
+        while let Some(byte) = seq.next_element::<u8>()? {
+            bytes.push(byte);
+            limits::check_blob_size(bytes.len(), false)?;
+        }
+        limits::allocate_blob(bytes.len(), false)?;
+        Ok((self.setter)(&bytes))
+    }
+}
+
+impl<'de, F, Value> DeserializeSeed<'de> for DataVisitor<F>
+where
+    F: FnOnce(&[u8]) -> Value,
+{
+    type Value = Value;
+
+    fn deserialize<D>(mut self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let human_readable = deserializer.is_human_readable();
+        self.encoding = self.encoding.resolve(human_readable);
+        match self.encoding {
+            DataEncoding::Auto | DataEncoding::Bytes => deserializer.deserialize_byte_buf(self),
+            // Human-readable formats are self-describing, so arrays of bytes are accepted as well
+            _ if human_readable => deserializer.deserialize_any(self),
+            _ => deserializer.deserialize_str(self),
+        }
+    }
 }
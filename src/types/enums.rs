@@ -1,29 +1,40 @@
-use capnp::schema::{EnumSchema, Enumerant};
+use capnp::schema::EnumSchema;
 use serde::de::{DeserializeSeed, Visitor};
 use tracing::trace;
 
+use crate::{Options, annotations::enumerant_name, error::de_capnp, naming::Naming};
+
+/// Reads an enum value and passes its ordinal to the setter.
+///
+/// Ordinals unknown to the schema are accepted, as they may have been written by a peer with a
+/// newer version of the schema.
 pub(super) struct EnumVisitor<F> {
     schema: EnumSchema,
+    naming: Naming,
     setter: F,
 }
 
 impl<F, Value> EnumVisitor<F>
 where
-    F: FnOnce(Enumerant) -> Value,
+    F: FnOnce(u16) -> Value,
 {
-    pub(super) fn new(schema: EnumSchema, setter: F) -> Self {
-        Self { schema, setter }
+    pub(super) fn new(schema: EnumSchema, options: &Options, setter: F) -> Self {
+        Self {
+            schema,
+            naming: Naming::new(options),
+            setter,
+        }
     }
 }
 
 impl<'de, F, Value> Visitor<'de> for EnumVisitor<F>
 where
-    F: FnOnce(Enumerant) -> Value,
+    F: FnOnce(u16) -> Value,
 {
     type Value = Value;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "enum")
+        write!(formatter, "enumerant name or ordinal")
     }
 
     fn visit_enum<A>(self, data: A) -> Result<Self::Value, A::Error>
@@ -33,8 +44,7 @@ where
         trace!("EnumVisitor::visit_enum");
         // Cap'n Proto doesn't support data attached to enum variants, so we can
         // ignore that part
-        data.variant_seed(self)
-            .inspect_err(|err| tracing::error!("{err}"))
+        data.variant_seed(IdentifierSeed(self))
             .map_err(serde::de::Error::custom)
             .map(|(enumerant, _)| enumerant)
     }
@@ -44,39 +54,67 @@ where
         E: serde::de::Error,
     {
         trace!("EnumVisitor::visit_str");
-        let enumerant = self
-            .schema
-            .get_enumerants()
-            .inspect_err(|err| tracing::error!("{err}"))
-            .map_err(serde::de::Error::custom)?
-            .iter()
-            .find(|enumerant| enumerant.get_proto().get_name().unwrap().to_str().unwrap() == value)
-            .ok_or_else(|| serde::de::Error::custom("Unknown enumerant"))?;
-
-        Ok((self.setter)(enumerant))
+        let mut candidates = Vec::new();
+        for enumerant in self.schema.get_enumerants().map_err(de_capnp)? {
+            let names = enumerant_name(enumerant, self.naming.convention)
+                .and_then(|name| Ok((name, enumerant.get_proto().get_name()?.to_str()?)));
+            let (name, schema_name) = names.map_err(de_capnp)?;
+            candidates.push((enumerant.get_ordinal(), name, schema_name));
+        }
+        let ordinal = self
+            .naming
+            .find(value, candidates)
+            .ok_or_else(|| E::custom(format!("unknown enumerant `{value}`")))?;
+
+        Ok((self.setter)(ordinal))
     }
 
-    fn visit_string<E>(self, value: String) -> Result<Self::Value, E>
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        trace!("EnumVisitor::visit_str");
-        let enumerant = self
-            .schema
-            .get_enumerants()
-            .inspect_err(|err| tracing::error!("{err}"))
-            .map_err(serde::de::Error::custom)?
-            .iter()
-            .find(|enumerant| enumerant.get_proto().get_name().unwrap().to_str().unwrap() == value)
-            .ok_or_else(|| serde::de::Error::custom("Unknown enumerant"))?;
-
-        Ok((self.setter)(enumerant))
+        trace!("EnumVisitor::visit_u64 {value}");
+        let ordinal = u16::try_from(value)
+            .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(value), &self))?;
+
+        Ok((self.setter)(ordinal))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("EnumVisitor::visit_i64 {value}");
+        let value = u64::try_from(value)
+            .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(value), &self))?;
+        self.visit_u64(value)
     }
 }
 
 impl<'de, F, Value> DeserializeSeed<'de> for EnumVisitor<F>
 where
-    F: FnOnce(Enumerant) -> Value,
+    F: FnOnce(u16) -> Value,
+{
+    type Value = Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Names and ordinals are both accepted, so the format has to tell which one it is. Reading
+        // an identifier instead wouldn't help formats that aren't self-describing, which read
+        // variants in `deserialize_enum` only, and some compact formats like CBOR only accept
+        // strings as identifiers, which would lose ordinals unknown to the schema.
+        deserializer.deserialize_any(self)
+    }
+}
+
+/// Reads the variant of an externally tagged enum, which is an identifier.
+struct IdentifierSeed<F>(EnumVisitor<F>);
+
+impl<'de, F, Value> DeserializeSeed<'de> for IdentifierSeed<F>
+where
+    F: FnOnce(u16) -> Value,
 {
     type Value = Value;
 
@@ -84,8 +122,6 @@ where
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer
-            .deserialize_identifier(self)
-            .inspect_err(|err| tracing::error!("{err}"))
+        deserializer.deserialize_identifier(self.0)
     }
 }
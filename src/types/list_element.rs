@@ -4,22 +4,46 @@ use capnp::{
     schema::EnumSchema,
 };
 use serde::de::DeserializeSeed;
-use tracing::{error, trace};
+use tracing::trace;
 
-use crate::types::{STRUCT_ENUM_SCHEMA_FIELD_NAMES, enums::EnumVisitor};
+use crate::{Options, error::de_capnp, types::enums::EnumVisitor};
 
 use super::{
-    bools::BoolVisitor, data::DataVisitor, num::NumVisitor, seq::SeqVisitor,
-    structs::StructVisitor, text::TextVisitor, void::VoidVisitor,
+    bools::BoolVisitor,
+    data::DataVisitor,
+    num::NumVisitor,
+    seq::{ListSlot, SeqVisitor},
+    structs::StructVisitor,
+    text::TextVisitor,
+    unsupported,
+    void::VoidVisitor,
 };
 
-pub(super) struct ElementSeed<'a> {
+pub(super) struct ElementSeed<'a, 'o> {
     pub(super) list_builder: capnp::dynamic_list::Builder<'a>,
     pub(super) index: u32,
     pub(super) ty: capnp::introspect::Type,
+    pub(super) options: &'o Options,
 }
 
-impl<'a, 'de> DeserializeSeed<'de> for &mut ElementSeed<'a> {
+/// A list element of a list of lists.
+struct ElementSlot<'a> {
+    list_builder: capnp::dynamic_list::Builder<'a>,
+    index: u32,
+}
+
+impl ListSlot for ElementSlot<'_> {
+    fn init(&mut self, size: u32) -> capnp::Result<capnp::dynamic_list::Builder<'_>> {
+        let builder = self.list_builder.reborrow().init(self.index, size)?;
+        if let capnp::dynamic_value::Builder::List(list_builder) = builder {
+            Ok(list_builder)
+        } else {
+            Err(capnp::Error::failed("Internal error".to_owned()))
+        }
+    }
+}
+
+impl<'a, 'de> DeserializeSeed<'de> for &mut ElementSeed<'a, '_> {
     type Value = ();
 
     fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
@@ -27,200 +51,136 @@ impl<'a, 'de> DeserializeSeed<'de> for &mut ElementSeed<'a> {
         D: serde::Deserializer<'de>,
     {
         trace!("CapnpSerdeElementSeed::deserialize {:?}", self.ty);
+        if self.index >= self.list_builder.len() {
+            // The sequence visitor allocates the list with the final number of elements
+            return Err(serde::de::Error::custom(format!(
+                "Internal error: list index {} is out of bounds",
+                self.index
+            )));
+        }
         match self.ty.which() {
             TypeVariant::List(inner_ty) => {
-                let list_builder = self.list_builder.reborrow();
-                let seed = SeqVisitor {
-                    inner_ty,
-                    generator: |size| -> capnp::Result<capnp::dynamic_list::Builder<'_>> {
-                        let builder = list_builder.init(self.index, size)?;
-                        if let capnp::dynamic_value::Builder::List(list_builder) = builder {
-                            Ok(list_builder)
-                        } else {
-                            Err(capnp::Error::failed("Internal error".to_owned()))
-                        }
-                    },
+                let slot = ElementSlot {
+                    list_builder: self.list_builder.reborrow(),
+                    index: self.index,
                 };
-                seed.deserialize(deserializer)
-                    .inspect_err(|err| error!("{err}"))?;
+                let seed = SeqVisitor::new(inner_ty, self.options, slot);
+                seed.deserialize(deserializer)?;
             }
             TypeVariant::Text => {
                 let list_builder = self.list_builder.reborrow();
-                deserializer
-                    .deserialize_str(TextVisitor::new(|s: &str| -> capnp::Result<()> {
-                        let dynamic_value::Builder::Text(mut text_builder) =
-                            list_builder.init(self.index, s.len() as _)?
-                        else {
-                            return Err(capnp::Error::failed("Internal error".to_owned()));
-                        };
+                TextVisitor::new(self.options, |s: &str| -> capnp::Result<()> {
+                    let dynamic_value::Builder::Text(mut text_builder) =
+                        list_builder.init(self.index, s.len() as _)?
+                    else {
+                        return Err(capnp::Error::failed("Internal error".to_owned()));
+                    };
 
-                        text_builder.push_str(s);
-                        Ok(())
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                    text_builder.push_str(s);
+                    Ok(())
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Data => {
-                deserializer
-                    .deserialize_bytes(DataVisitor::new(|s: &[u8]| -> capnp::Result<()> {
+                DataVisitor::new(
+                    self.options.data_encoding,
+                    |s: &[u8]| -> capnp::Result<()> {
                         self.list_builder
                             .set(self.index, dynamic_value::Reader::Data(s))?;
                         Ok(())
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                    },
+                )
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Bool => {
-                deserializer
-                    .deserialize_bool(BoolVisitor::new(|b| {
-                        self.list_builder
-                            .set(self.index, dynamic_value::Reader::Bool(b))
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                BoolVisitor::new(self.options, |b| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Bool(b))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Int8 => {
-                deserializer
-                    .deserialize_i8(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Int8(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Int8(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Int16 => {
-                deserializer
-                    .deserialize_i16(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Int16(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Int16(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Int32 => {
-                deserializer
-                    .deserialize_i32(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Int32(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Int32(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Int64 => {
-                deserializer
-                    .deserialize_i64(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Int64(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Int64(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::UInt8 => {
-                deserializer
-                    .deserialize_u8(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::UInt8(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::UInt8(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::UInt16 => {
-                deserializer
-                    .deserialize_u16(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::UInt16(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::UInt16(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::UInt32 => {
-                deserializer
-                    .deserialize_u32(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::UInt32(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::UInt32(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::UInt64 => {
-                deserializer
-                    .deserialize_u64(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::UInt64(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::UInt64(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Float32 => {
-                deserializer
-                    .deserialize_f32(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Float32(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Float32(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Float64 => {
-                deserializer
-                    .deserialize_f64(NumVisitor::new(|num| {
-                        if let Some(num) = num {
-                            self.list_builder
-                                .set(self.index, dynamic_value::Reader::Float64(num))
-                        } else {
-                            Ok(())
-                        }
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                NumVisitor::new(self.options, |num| {
+                    self.list_builder
+                        .set(self.index, dynamic_value::Reader::Float64(num))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
             }
             TypeVariant::Struct(_) => {
                 let seed = StructVisitor {
@@ -228,56 +188,35 @@ impl<'a, 'de> DeserializeSeed<'de> for &mut ElementSeed<'a> {
                         .list_builder
                         .reborrow()
                         .get(self.index)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?,
+                        .map_err(de_capnp)?,
                     ty: self.ty,
+                    group: None,
+                    options: self.options,
                 };
-                seed.deserialize(deserializer)
-                    .inspect_err(|err| error!("{err}"))?;
+                seed.deserialize(deserializer)?;
             }
             TypeVariant::Void => {
                 deserializer
                     .deserialize_unit(VoidVisitor::new(|| {
                         self.list_builder
                             .set(self.index, dynamic_value::Reader::Void)
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                    }))?
+                    .map_err(de_capnp)?;
             }
             TypeVariant::Enum(raw_schema) => {
                 let schema = EnumSchema::new(raw_schema);
-                let proto = schema.get_proto();
-                let enumerant_names = STRUCT_ENUM_SCHEMA_FIELD_NAMES.insert(proto.get_id(), |_| {
-                    let enumerants = schema.get_enumerants().unwrap();
-                    enumerants
-                        .iter()
-                        .map(|enumerant| {
-                            enumerant.get_proto().get_name().unwrap().to_str().unwrap()
-                        })
-                        .collect::<Box<_>>()
-                });
-
-                deserializer
-                    .deserialize_enum(
-                        proto.get_display_name().unwrap().to_str().unwrap(),
-                        enumerant_names,
-                        EnumVisitor::new(schema, |enumerant| {
-                            self.list_builder.set(
-                                self.index,
-                                dynamic_value::Reader::Enum(Enum::new(
-                                    enumerant.get_ordinal(),
-                                    schema,
-                                )),
-                            )
-                        }),
+                EnumVisitor::new(schema, self.options, |ordinal| {
+                    self.list_builder.set(
+                        self.index,
+                        dynamic_value::Reader::Enum(Enum::new(ordinal, schema)),
                     )
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
+            }
+            ty @ (TypeVariant::AnyPointer | TypeVariant::Capability) => {
+                return Err(unsupported(ty));
             }
-            TypeVariant::AnyPointer => unimplemented!(),
-            TypeVariant::Capability => unimplemented!(),
         }
 
         Ok(())
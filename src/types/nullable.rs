@@ -0,0 +1,63 @@
+use serde::de::{DeserializeSeed, Visitor};
+use tracing::trace;
+
+/// Wraps the seed of a pointer value, so that a missing value (`null` in JSON) leaves the pointer unset.
+///
+/// Yields `None` for a missing value.
+pub(crate) struct NullableSeed<S> {
+    seed: S,
+}
+
+impl<S> NullableSeed<S> {
+    pub(crate) fn new(seed: S) -> Self {
+        Self { seed }
+    }
+}
+
+impl<'de, S> Visitor<'de> for NullableSeed<S>
+where
+    S: DeserializeSeed<'de>,
+{
+    type Value = Option<S::Value>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "pointer or null")
+    }
+
+    fn visit_none<E>(self) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("NullableSeed::visit_none");
+        Ok(None)
+    }
+
+    fn visit_unit<E>(self) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("NullableSeed::visit_unit");
+        Ok(None)
+    }
+
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        self.seed.deserialize(deserializer).map(Some)
+    }
+}
+
+impl<'de, S> DeserializeSeed<'de> for NullableSeed<S>
+where
+    S: DeserializeSeed<'de>,
+{
+    type Value = Option<S::Value>;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_option(self)
+    }
+}
@@ -1,56 +1,175 @@
 use std::marker::PhantomData;
 
 use num_traits::NumCast;
-use serde::de::Visitor;
+use serde::de::{DeserializeSeed, Unexpected, Visitor};
 use tracing::trace;
 
+use crate::{NonFiniteFloats, NumericOverflow, Options};
+
+/// The primitive number types of Cap'n Proto.
+pub(super) trait Number: NumCast {
+    /// Floats can't be written as decimal strings, but NaN and infinite values may be strings.
+    const FLOAT: bool;
+
+    /// 64-bit integers, which are written as decimal strings with `int64_as_string`.
+    const INT64: bool;
+
+    /// Calls the `deserialize_*` method for the type.
+    fn deserialize<'de, D, V>(deserializer: D, visitor: V) -> Result<V::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+        V: Visitor<'de>;
+
+    /// The closest value to an out of range value.
+    fn saturate(value: f64) -> Self;
+
+    /// The low bits of an integer, which fits into floats anyway.
+    fn wrap(value: i128) -> Self;
+}
+
+macro_rules! number {
+    ($($ty:ty => $method:ident, $float:literal, $int64:literal;)*) => {
+        $(
+            impl Number for $ty {
+                const FLOAT: bool = $float;
+                const INT64: bool = $int64;
+
+                fn deserialize<'de, D, V>(deserializer: D, visitor: V) -> Result<V::Value, D::Error>
+                where
+                    D: serde::Deserializer<'de>,
+                    V: Visitor<'de>,
+                {
+                    deserializer.$method(visitor)
+                }
+
+                fn saturate(value: f64) -> Self {
+                    value.clamp(<$ty>::MIN as f64, <$ty>::MAX as f64) as $ty
+                }
+
+                fn wrap(value: i128) -> Self {
+                    value as $ty
+                }
+            }
+        )*
+    };
+}
+
+number! {
+    i8 => deserialize_i8, false, false;
+    i16 => deserialize_i16, false, false;
+    i32 => deserialize_i32, false, false;
+    i64 => deserialize_i64, false, true;
+    u8 => deserialize_u8, false, false;
+    u16 => deserialize_u16, false, false;
+    u32 => deserialize_u32, false, false;
+    u64 => deserialize_u64, false, true;
+    f32 => deserialize_f32, true, false;
+    f64 => deserialize_f64, true, false;
+}
+
+/// Reads a number, which has to fit into `N` unless the [`NumericOverflow`] policy says otherwise.
+/// Integers may also be given as floats without a fractional part or as decimal strings, NaN and
+/// infinite floats as the strings of [`NonFiniteFloats::Strings`].
+///
+/// In lenient mode, floats may be decimal strings too, as may integers with a fraction of zero.
 pub(super) struct NumVisitor<N, R, F> {
     setter: F,
+    /// Expect strings even if the format isn't human readable: decimal strings for 64-bit integers
+    /// or the strings of [`NonFiniteFloats::Strings`] along with numbers for floats.
+    strings: bool,
+    lenient: bool,
+    overflow: NumericOverflow,
     _marker: PhantomData<(N, R)>,
 }
 
-impl<N, R, F> NumVisitor<N, R, F> {
-    pub(super) fn new(setter: F) -> Self {
+impl<N, R, F> NumVisitor<N, R, F>
+where
+    N: Number,
+{
+    pub(super) fn new(options: &Options, setter: F) -> Self {
         Self {
             setter,
+            strings: if N::FLOAT {
+                options.non_finite_floats == NonFiniteFloats::Strings
+            } else {
+                N::INT64 && options.int64_as_string
+            },
+            lenient: options.lenient,
+            overflow: options.numeric_overflow,
             _marker: PhantomData,
         }
     }
 }
 
-impl<'de, N, R, F> Visitor<'de> for NumVisitor<N, R, F>
+impl<N, R, F> NumVisitor<N, R, F>
 where
-    N: NumCast,
-    F: FnOnce(Option<N>) -> R,
+    N: Number,
 {
-    type Value = R;
+    fn integer(&self, value: i128) -> Option<N> {
+        N::from(value).or_else(|| self.out_of_range(value as f64, Some(value)))
+    }
 
-    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "{}", std::any::type_name::<N>())
+    /// Converts a float, which has to be exact for integers.
+    fn float(&self, value: f64) -> Option<N> {
+        if !N::FLOAT && value.fract() != 0.0 {
+            return None;
+        }
+        // Casts to `Float32` are infinite instead of failing
+        let converted = N::from(value).filter(|converted| {
+            !value.is_finite() || converted.to_f64().is_some_and(f64::is_finite)
+        });
+        converted.or_else(|| {
+            let wrapped = (value.abs() < i128::MAX as f64).then_some(value as i128);
+            self.out_of_range(value, wrapped)
+        })
     }
 
-    fn visit_u8<E>(self, v: u8) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        trace!("NumVisitor::visit_u8 {v:?}");
-        Ok((self.setter)(N::from(v)))
+    fn out_of_range(&self, value: f64, wrapped: Option<i128>) -> Option<N> {
+        match self.overflow {
+            NumericOverflow::Error => None,
+            NumericOverflow::Saturate => Some(N::saturate(value)),
+            NumericOverflow::Wrap => wrapped.filter(|_| !N::FLOAT).map(N::wrap),
+        }
     }
+}
 
-    fn visit_u16<E>(self, v: u16) -> Result<Self::Value, E>
+impl<N, R, F> NumVisitor<N, R, F>
+where
+    N: Number,
+    F: FnOnce(N) -> R,
+{
+    fn set<E>(self, value: Option<N>, unexpected: Unexpected) -> Result<R, E>
     where
         E: serde::de::Error,
     {
-        trace!("NumVisitor::visit_u16 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        match value {
+            Some(value) => Ok((self.setter)(value)),
+            None => Err(E::invalid_value(unexpected, &self)),
+        }
     }
+}
 
-    fn visit_u32<E>(self, v: u32) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        trace!("NumVisitor::visit_u32 {v:?}");
-        Ok((self.setter)(N::from(v)))
+impl<'de, N, R, F> Visitor<'de> for NumVisitor<N, R, F>
+where
+    N: Number,
+    F: FnOnce(N) -> R,
+{
+    type Value = R;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if N::FLOAT {
+            write!(
+                formatter,
+                "{}, \"NaN\", \"Infinity\" or \"-Infinity\"",
+                std::any::type_name::<N>()
+            )
+        } else {
+            write!(
+                formatter,
+                "{} or a decimal string",
+                std::any::type_name::<N>()
+            )
+        }
     }
 
     fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
@@ -58,7 +177,8 @@ where
         E: serde::de::Error,
     {
         trace!("NumVisitor::visit_u64 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        let value = self.integer(v.into());
+        self.set(value, Unexpected::Unsigned(v))
     }
 
     fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E>
@@ -66,62 +186,84 @@ where
         E: serde::de::Error,
     {
         trace!("NumVisitor::visit_u128 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        let value = match i128::try_from(v) {
+            Ok(v) => self.integer(v),
+            Err(_) => N::from(v).or_else(|| self.out_of_range(v as f64, Some(v as i128))),
+        };
+        self.set(value, Unexpected::Other("u128"))
     }
 
-    fn visit_i8<E>(self, v: i8) -> Result<Self::Value, E>
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        trace!("NumVisitor::visit_i8 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        trace!("NumVisitor::visit_i64 {v:?}");
+        let value = self.integer(v.into());
+        self.set(value, Unexpected::Signed(v))
     }
 
-    fn visit_i16<E>(self, v: i16) -> Result<Self::Value, E>
+    fn visit_i128<E>(self, v: i128) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        trace!("NumVisitor::visit_i16 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        trace!("NumVisitor::visit_i128 {v:?}");
+        let value = self.integer(v);
+        self.set(value, Unexpected::Other("i128"))
     }
 
-    fn visit_i32<E>(self, v: i32) -> Result<Self::Value, E>
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        trace!("NumVisitor::visit_i32 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        trace!("NumVisitor::visit_f64 {v:?}");
+        let value = self.float(v);
+        self.set(value, Unexpected::Float(v))
     }
 
-    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        trace!("NumVisitor::visit_i64 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        trace!("NumVisitor::visit_str {v:?}");
+        let value = if N::FLOAT {
+            NonFiniteFloats::decode(v)
+                .or_else(|| self.lenient.then(|| parse_finite(v)).flatten())
+                .and_then(|v| self.float(v))
+        } else {
+            match v.parse::<i128>() {
+                Ok(v) => self.integer(v),
+                Err(_) if self.lenient => parse_finite(v).and_then(|v| self.float(v)),
+                Err(_) => None,
+            }
+        };
+        self.set(value, Unexpected::Str(v))
     }
+}
 
-    fn visit_i128<E>(self, v: i128) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        trace!("NumVisitor::visit_i128 {v:?}");
-        Ok((self.setter)(N::from(v)))
-    }
+impl<'de, N, R, F> DeserializeSeed<'de> for NumVisitor<N, R, F>
+where
+    N: Number,
+    F: FnOnce(N) -> R,
+{
+    type Value = R;
 
-    fn visit_f32<E>(self, v: f32) -> Result<Self::Value, E>
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
     where
-        E: serde::de::Error,
+        D: serde::Deserializer<'de>,
     {
-        trace!("NumVisitor::visit_f32 {v:?}");
-        Ok((self.setter)(N::from(v)))
+        // Strings can only be told apart from numbers by self-describing formats. 64-bit integers
+        // written as strings are always strings, so they can be read by any format.
+        if self.lenient || deserializer.is_human_readable() || (self.strings && N::FLOAT) {
+            deserializer.deserialize_any(self)
+        } else if self.strings {
+            deserializer.deserialize_str(self)
+        } else {
+            N::deserialize(deserializer, self)
+        }
     }
+}
 
-    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
-    where
-        E: serde::de::Error,
-    {
-        trace!("NumVisitor::visit_f64 {v:?}");
-        Ok((self.setter)(N::from(v)))
-    }
+/// Parses a decimal string, leaving NaN and infinite values to [`NonFiniteFloats::decode`].
+fn parse_finite(text: &str) -> Option<f64> {
+    text.parse::<f64>().ok().filter(|value| value.is_finite())
 }
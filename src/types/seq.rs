@@ -1,44 +1,137 @@
-use capnp::introspect::TypeVariant;
-use serde::de::{DeserializeSeed, SeqAccess, Visitor};
-use tracing::{error, trace};
+use std::{convert::identity, marker::PhantomData};
 
-use crate::types::enums::EnumVisitor;
+use capnp::{
+    any_pointer, any_pointer_list, dynamic_list, dynamic_value,
+    introspect::{Type, TypeVariant},
+    message::{self, HeapAllocator},
+    schema_capnp::value,
+};
+use serde::de::{
+    DeserializeSeed, Deserializer, Error as _, IntoDeserializer, MapAccess, SeqAccess, Unexpected,
+    Visitor,
+    value::{BytesDeserializer, MapAccessDeserializer},
+};
+use tracing::trace;
 
-use super::{list_element::ElementSeed, type_variant_to_str};
+use crate::{
+    ListMerge, Options,
+    error::{self, Segment, de_capnp},
+    limits,
+    types::enums::EnumVisitor,
+};
 
-// Sequences only know their length at deserialization time, so we have to delay
-// the initialization of the field
-pub(crate) struct SeqVisitor<F> {
-    pub(super) inner_ty: capnp::introspect::Type,
-    pub(super) generator: F,
+use super::{
+    bools::BoolVisitor,
+    content::{Content, ContentDeserializer},
+    data::DataVisitor,
+    list_element::ElementSeed,
+    nullable::NullableSeed,
+    num::{NumVisitor, Number},
+    text::TextVisitor,
+    type_variant_to_str, unsupported,
+};
+
+/// The pointer a list is built in. Sequences only know their length at deserialization time, so
+/// the list is initialized late, and again if the size hint of the sequence was wrong.
+pub(crate) trait ListSlot {
+    fn init(&mut self, size: u32) -> capnp::Result<capnp::dynamic_list::Builder<'_>>;
+
+    /// The list that is already set, for merging into an existing message.
+    fn get(&mut self) -> capnp::Result<Option<capnp::dynamic_list::Reader<'_>>> {
+        Ok(None)
+    }
+}
+
+pub(crate) struct SeqVisitor<'o, S> {
+    inner_ty: capnp::introspect::Type,
+    options: &'o Options,
+    slot: S,
+    /// Whether the format is human readable, for replaying buffered elements.
+    human_readable: bool,
 }
 
-impl<'a, F> SeqVisitor<F>
+impl<'o, S> SeqVisitor<'o, S>
 where
-    F: FnOnce(u32) -> capnp::Result<capnp::dynamic_list::Builder<'a>>,
+    S: ListSlot,
 {
-    pub(crate) fn new(inner_ty: capnp::introspect::Type, generator: F) -> Self {
+    pub(crate) fn new(inner_ty: capnp::introspect::Type, options: &'o Options, slot: S) -> Self {
         Self {
             inner_ty,
-            generator,
+            options,
+            slot,
+            human_readable: false,
         }
     }
 }
 
-impl<'a, 'de, F> Visitor<'de> for SeqVisitor<F>
+impl<'de, S> Visitor<'de> for SeqVisitor<'_, S>
 where
-    F: FnOnce(u32) -> capnp::Result<capnp::dynamic_list::Builder<'a>>,
+    S: ListSlot,
 {
     type Value = ();
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let TypeVariant::List(inner_ty) = self.inner_ty.which() else {
-            return Err(std::fmt::Error);
-        };
-        write!(formatter, "List({})", type_variant_to_str(inner_ty.which()))
+        write!(
+            formatter,
+            "List({})",
+            type_variant_to_str(self.inner_ty.which())
+        )?;
+        if self.options.lenient {
+            write!(formatter, " or a single element")?;
+        }
+        Ok(())
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(v.into_deserializer(), Unexpected::Bool(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(v.into_deserializer(), Unexpected::Signed(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(v.into_deserializer(), Unexpected::Unsigned(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(v.into_deserializer(), Unexpected::Float(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(v.into_deserializer(), Unexpected::Str(v))
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        self.single(BytesDeserializer::new(v), Unexpected::Bytes(v))
     }
 
-    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        self.single(MapAccessDeserializer::new(map), Unexpected::Map)
+    }
+
+    fn visit_seq<A>(mut self, mut seq: A) -> Result<Self::Value, A::Error>
     where
         A: SeqAccess<'de>,
     {
@@ -46,72 +139,126 @@ where
             "CapnpSerdeSeqVisitor::visit_seq size = {:?}",
             seq.size_hint()
         );
+        let nullable = self.nullable();
+        if self.appending() {
+            return self.append(seq, nullable);
+        }
         if let Some(size) = seq.size_hint() {
-            // The try-operator confuses the borrow checker here, so we can't use it!
-            let list_builder = match (self.generator)(size as u32) {
-                Ok(list_builder) => list_builder,
-                Err(err) => {
-                    error!("{err}");
-                    return Err(serde::de::Error::custom(err));
-                }
-            };
-            let mut index = 0;
             let mut seed = ElementSeed {
-                list_builder,
+                list_builder: init_list(
+                    &mut self.slot,
+                    self.inner_ty,
+                    limits::list_hint(self.inner_ty, size),
+                )?,
                 index: 0,
                 ty: self.inner_ty,
+                options: self.options,
             };
-            loop {
-                seed.index = index;
-                match seq.next_element_seed(&mut seed) {
-                    Err(err) => {
-                        error!("{err}");
-                        return Err(err);
-                    }
-                    Ok(None) => break,
-                    Ok(Some(())) => {
-                        index += 1;
-                    }
+            let len = seed.list_builder.len();
+            let mut count = 0;
+            while count < len {
+                seed.index = count;
+                let element = error::within(
+                    || Segment::Index(count),
+                    self.inner_ty,
+                    || {
+                        if nullable {
+                            seq.next_element_seed(NullableSeed::new(&mut seed))
+                                .map(|element| element.map(|_| ()))
+                        } else {
+                            seq.next_element_seed(&mut seed)
+                        }
+                    },
+                )?;
+                if element.is_none() {
+                    break;
+                }
+                count += 1;
+            }
+            // Serde only promises a hint, so the list is allocated again if it was wrong
+            let mut rest = Vec::new();
+            if count == len {
+                while let Some(element) = next_element(
+                    &mut seq,
+                    PhantomData::<Content>,
+                    count as usize + rest.len(),
+                    self.inner_ty,
+                )? {
+                    rest.push(element);
                 }
             }
+            if count < len || !rest.is_empty() {
+                trace!(
+                    "CapnpSerdeSeqVisitor::visit_seq size = {}",
+                    count as usize + rest.len()
+                );
+                let prefix =
+                    Prefix::new(seed.list_builder.into_reader(), count).map_err(de_capnp)?;
+                self.fill(Some(prefix), rest, nullable)?;
+            }
             Ok(())
         } else {
             match self.inner_ty.which() {
                 TypeVariant::Void => {
                     let mut count = 0;
-                    while seq.next_element::<()>()?.is_some() {
+                    while next_element(&mut seq, PhantomData::<()>, count, self.inner_ty)?.is_some()
+                    {
                         count += 1;
                     }
-                    (self.generator)(count)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)
-                        .map(|_| ())
-                }
-                TypeVariant::Bool => iterate_simple::<'_, '_, bool, _, _>(self.generator, seq),
-                TypeVariant::Int8 => iterate_simple::<'_, '_, i8, _, _>(self.generator, seq),
-                TypeVariant::Int16 => iterate_simple::<'_, '_, i16, _, _>(self.generator, seq),
-                TypeVariant::Int32 => iterate_simple::<'_, '_, i32, _, _>(self.generator, seq),
-                TypeVariant::Int64 => iterate_simple::<'_, '_, i64, _, _>(self.generator, seq),
-                TypeVariant::UInt8 => iterate_simple::<'_, '_, u8, _, _>(self.generator, seq),
-                TypeVariant::UInt16 => iterate_simple::<'_, '_, u16, _, _>(self.generator, seq),
-                TypeVariant::UInt32 => iterate_simple::<'_, '_, u32, _, _>(self.generator, seq),
-                TypeVariant::UInt64 => iterate_simple::<'_, '_, u64, _, _>(self.generator, seq),
-                TypeVariant::Float32 => iterate_simple::<'_, '_, f32, _, _>(self.generator, seq),
-                TypeVariant::Float64 => iterate_simple::<'_, '_, f64, _, _>(self.generator, seq),
+                    init_list(&mut self.slot, self.inner_ty, count).map(|_| ())
+                }
+                TypeVariant::Bool => iterate_simple(&mut self.slot, seq, self.inner_ty, || {
+                    BoolVisitor::new(self.options, identity)
+                }),
+                TypeVariant::Int8 => {
+                    iterate_numbers::<i8, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::Int16 => {
+                    iterate_numbers::<i16, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::Int32 => {
+                    iterate_numbers::<i32, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::Int64 => {
+                    iterate_numbers::<i64, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::UInt8 => {
+                    iterate_numbers::<u8, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::UInt16 => {
+                    iterate_numbers::<u16, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::UInt32 => {
+                    iterate_numbers::<u32, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::UInt64 => {
+                    iterate_numbers::<u64, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::Float32 => {
+                    iterate_numbers::<f32, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
+                TypeVariant::Float64 => {
+                    iterate_numbers::<f64, _, _>(&mut self.slot, seq, self.inner_ty, self.options)
+                }
                 TypeVariant::Text => {
                     let mut values = Vec::new();
-                    while let Some(value) = seq.next_element::<String>()? {
+                    while let Some(value) = next_element(
+                        &mut seq,
+                        NullableSeed::new(TextVisitor::new(self.options, str::to_owned)),
+                        values.len(),
+                        self.inner_ty,
+                    )? {
                         values.push(value);
                     }
-                    let mut list_builder = (self.generator)(values.len() as _)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?;
+                    let mut list_builder = init_list(&mut self.slot, self.inner_ty, values.len())?;
                     for (index, value) in values.into_iter().enumerate() {
+                        let Some(value) = value else {
+                            continue;
+                        };
                         let capnp::dynamic_value::Builder::Text(mut text_builder) = list_builder
                             .reborrow()
                             .init(index as u32, value.len() as u32)
-                            .inspect_err(|err| error!("{err}"))
-                            .map_err(serde::de::Error::custom)?
+                            .map_err(de_capnp)?
                         else {
                             return Err(serde::de::Error::custom("Internal error".to_owned()));
                         };
@@ -121,98 +268,337 @@ where
                 }
                 TypeVariant::Data => {
                     let mut values = Vec::new();
-                    while let Some(value) = seq.next_element::<serde_bytes::ByteBuf>()? {
+                    while let Some(value) = next_element(
+                        &mut seq,
+                        NullableSeed::new(DataVisitor::new(
+                            self.options.data_encoding,
+                            <[u8]>::to_vec,
+                        )),
+                        values.len(),
+                        self.inner_ty,
+                    )? {
                         values.push(value);
                     }
-                    let mut list_builder = (self.generator)(values.len() as _)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?;
+                    let mut list_builder = init_list(&mut self.slot, self.inner_ty, values.len())?;
                     for (index, value) in values.into_iter().enumerate() {
+                        let Some(value) = value else {
+                            continue;
+                        };
                         list_builder
                             .reborrow()
                             .set(
                                 index as u32,
                                 capnp::dynamic_value::Reader::Data(value.as_ref()),
                             )
-                            .inspect_err(|err| error!("{err}"))
-                            .map_err(serde::de::Error::custom)?
+                            .map_err(de_capnp)?
                     }
                     Ok(())
                 }
                 TypeVariant::Enum(raw_enum_schema) => {
+                    let schema = raw_enum_schema.into();
                     let mut values = Vec::new();
-                    while seq
-                        .next_element_seed(EnumVisitor::new(raw_enum_schema.into(), |value| {
-                            values.push(value);
-                        }))?
-                        .is_some()
-                    {}
-                    let mut list_builder = (self.generator)(values.len() as _)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?;
+                    while let Some(value) = next_element(
+                        &mut seq,
+                        EnumVisitor::new(schema, self.options, identity),
+                        values.len(),
+                        self.inner_ty,
+                    )? {
+                        values.push(value);
+                    }
+                    let mut list_builder = init_list(&mut self.slot, self.inner_ty, values.len())?;
                     for (index, value) in values.into_iter().enumerate() {
                         list_builder
                             .reborrow()
                             .set(
                                 index as u32,
                                 capnp::dynamic_value::Reader::Enum(
-                                    capnp::dynamic_value::Enum::new(
-                                        value.get_ordinal(),
-                                        value.get_containing_enum(),
-                                    ),
+                                    capnp::dynamic_value::Enum::new(value, schema),
                                 ),
                             )
-                            .inspect_err(|err| error!("{err}"))
-                            .map_err(serde::de::Error::custom)?
+                            .map_err(de_capnp)?
                     }
                     Ok(())
                 }
-                TypeVariant::Struct(_) | TypeVariant::List(_) => Err(serde::de::Error::custom(
-                    "Cap'n Proto encoding requires pointer lists to declare their size before the actual data. Your decoder does not provide this information.",
-                )),
-                TypeVariant::AnyPointer => unimplemented!(),
-                TypeVariant::Capability => unimplemented!(),
+                TypeVariant::Struct(_) | TypeVariant::List(_) => {
+                    // The list has to be allocated with its size, so the elements are buffered
+                    let mut elements = Vec::new();
+                    while let Some(element) = next_element(
+                        &mut seq,
+                        PhantomData::<Content>,
+                        elements.len(),
+                        self.inner_ty,
+                    )? {
+                        elements.push(element);
+                    }
+                    self.fill(None, elements, nullable)
+                }
+                ty @ (TypeVariant::AnyPointer | TypeVariant::Capability) => Err(unsupported(ty)),
             }
         }
     }
 }
 
-impl<'a, 'de, F> DeserializeSeed<'de> for SeqVisitor<F>
+impl<S> SeqVisitor<'_, S>
+where
+    S: ListSlot,
+{
+    /// Initializes the list with the elements of a prefix, if any, followed by buffered elements.
+    fn fill<E>(
+        &mut self,
+        prefix: Option<Prefix>,
+        elements: Vec<Content>,
+        nullable: bool,
+    ) -> Result<(), E>
+    where
+        E: serde::de::Error,
+    {
+        let start = prefix.as_ref().map_or(0, |prefix| prefix.len);
+        let len = start as usize + elements.len();
+        let mut seed = ElementSeed {
+            list_builder: init_list(&mut self.slot, self.inner_ty, len)?,
+            index: 0,
+            ty: self.inner_ty,
+            options: self.options,
+        };
+        if let Some(prefix) = prefix {
+            prefix.copy_to(&mut seed.list_builder).map_err(de_capnp)?;
+        }
+        for (index, element) in (start..).zip(elements) {
+            seed.index = index;
+            let deserializer = ContentDeserializer::new(element, self.human_readable);
+            error::within(
+                || Segment::Index(index),
+                self.inner_ty,
+                || {
+                    if nullable {
+                        NullableSeed::new(&mut seed)
+                            .deserialize(deserializer)
+                            .map(|_| ())
+                    } else {
+                        (&mut seed).deserialize(deserializer)
+                    }
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Whether the elements may be null: pointer elements may be, lists of structs are stored
+    /// inline.
+    fn nullable(&self) -> bool {
+        matches!(
+            self.inner_ty.which(),
+            TypeVariant::Text | TypeVariant::Data | TypeVariant::List(_)
+        )
+    }
+
+    /// Whether the elements are appended to the list that is already set.
+    fn appending(&self) -> bool {
+        self.options.merging && self.options.list_merge == ListMerge::Append
+    }
+
+    /// The elements of the list that is already set, if any.
+    fn existing<E>(&mut self) -> Result<Option<Prefix>, E>
+    where
+        E: serde::de::Error,
+    {
+        match self.slot.get().map_err(de_capnp)? {
+            Some(list) => Ok(Some(Prefix::new(list, list.len()).map_err(de_capnp)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Appends the elements of the sequence to the list that is already set.
+    fn append<'de, A>(mut self, mut seq: A, nullable: bool) -> Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let prefix = self.existing()?;
+        let start = prefix.as_ref().map_or(0, |prefix| prefix.len as usize);
+        let mut elements = Vec::new();
+        while let Some(element) = next_element(
+            &mut seq,
+            PhantomData::<Content>,
+            start + elements.len(),
+            self.inner_ty,
+        )? {
+            elements.push(element);
+        }
+        self.fill(prefix, elements, nullable)
+    }
+
+    /// Reads a value that isn't a sequence as a list of one element in lenient mode.
+    fn single<'de, D>(mut self, deserializer: D, unexpected: Unexpected) -> Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        trace!("SeqVisitor::single {unexpected}");
+        if !self.options.lenient {
+            return Err(D::Error::invalid_type(unexpected, &self));
+        }
+        if self.appending() {
+            let prefix = self.existing()?;
+            let index = prefix.as_ref().map_or(0, |prefix| prefix.len);
+            let element = error::within(
+                || Segment::Index(index),
+                self.inner_ty,
+                || PhantomData::<Content>.deserialize(deserializer),
+            )?;
+            let nullable = self.nullable();
+            return self.fill(prefix, vec![element], nullable);
+        }
+        let list_builder = init_list(&mut self.slot, self.inner_ty, 1)?;
+        let mut seed = ElementSeed {
+            list_builder,
+            index: 0,
+            ty: self.inner_ty,
+            options: self.options,
+        };
+        error::within(
+            || Segment::Index(0),
+            self.inner_ty,
+            || (&mut seed).deserialize(deserializer),
+        )
+    }
+}
+
+impl<'de, S> DeserializeSeed<'de> for SeqVisitor<'_, S>
 where
-    F: FnOnce(u32) -> capnp::Result<capnp::dynamic_list::Builder<'a>>,
+    S: ListSlot,
 {
     type Value = ();
 
-    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    fn deserialize<D>(mut self, deserializer: D) -> Result<Self::Value, D::Error>
     where
         D: serde::Deserializer<'de>,
     {
-        deserializer
-            .deserialize_seq(self)
-            .inspect_err(|err| error!("{err}"))?;
-        Ok(())
+        self.human_readable = deserializer.is_human_readable();
+        let _nested = limits::enter()?;
+        // Single values can only be told apart from sequences by self-describing formats
+        if self.options.lenient {
+            deserializer.deserialize_any(self)
+        } else {
+            deserializer.deserialize_seq(self)
+        }
     }
 }
 
-fn iterate_simple<'a, 'de, Value, F, A>(generator: F, mut seq: A) -> Result<(), A::Error>
+fn iterate_numbers<'de, N, S, A>(
+    slot: &mut S,
+    seq: A,
+    ty: Type,
+    options: &Options,
+) -> Result<(), A::Error>
+where
+    N: Number,
+    A: SeqAccess<'de>,
+    for<'b> capnp::dynamic_value::Reader<'b>: From<N>,
+    S: ListSlot,
+{
+    iterate_simple(slot, seq, ty, || NumVisitor::new(options, identity::<N>))
+}
+
+fn iterate_simple<'de, T, S, A>(
+    slot: &mut S,
+    mut seq: A,
+    ty: Type,
+    seed: impl Fn() -> T,
+) -> Result<(), A::Error>
 where
-    Value: serde::Deserialize<'de>,
+    T: DeserializeSeed<'de>,
     A: SeqAccess<'de>,
-    for<'b> capnp::dynamic_value::Reader<'b>: From<Value>,
-    F: FnOnce(u32) -> capnp::Result<capnp::dynamic_list::Builder<'a>>,
+    for<'b> capnp::dynamic_value::Reader<'b>: From<T::Value>,
+    S: ListSlot,
 {
     let mut values = Vec::new();
-    while let Some(value) = seq.next_element::<Value>()? {
+    while let Some(value) = next_element(&mut seq, seed(), values.len(), ty)? {
         values.push(value);
     }
-    let mut list_builder = generator(values.len() as _)
-        .inspect_err(|err| error!("{err}"))
-        .map_err(serde::de::Error::custom)?;
+    let mut list_builder = init_list(slot, ty, values.len())?;
     for (index, value) in values.into_iter().enumerate() {
         list_builder
             .set(index as u32, value.into())
-            .inspect_err(|err| error!("{err}"))
-            .map_err(serde::de::Error::custom)?;
+            .map_err(de_capnp)?;
     }
     Ok(())
 }
+
+/// Reads the next element of a list of the given element type, locating errors at its index.
+fn next_element<'de, S, A>(
+    seq: &mut A,
+    seed: S,
+    index: usize,
+    ty: Type,
+) -> Result<Option<S::Value>, A::Error>
+where
+    S: DeserializeSeed<'de>,
+    A: SeqAccess<'de>,
+{
+    seq.next_element_seed(seed)
+        .and_then(|element| {
+            if element.is_some() {
+                limits::check_list_length(index + 1)?;
+            }
+            Ok(element)
+        })
+        .map_err(|err| error::at(err, Segment::Index(index as u32), ty))
+}
+
+/// Initializes the list in the slot, within the limits of the options.
+fn init_list<S, E>(slot: &mut S, ty: Type, len: usize) -> Result<dynamic_list::Builder<'_>, E>
+where
+    S: ListSlot,
+    E: serde::de::Error,
+{
+    let len = limits::allocate_list(ty, len)?;
+    slot.init(len).map_err(de_capnp)
+}
+
+/// The first elements of a list that is initialized again, either because the size hint of the
+/// sequence was wrong or because elements are appended to it. They're copied out of the message
+/// first, as initializing the list zeroes them.
+struct Prefix {
+    /// A `Value` holding the copy of the list, which the dynamic API can read as any list type.
+    message: message::Builder<HeapAllocator>,
+    ty: Type,
+    len: u32,
+}
+
+impl Prefix {
+    fn new(list: dynamic_list::Reader<'_>, len: u32) -> capnp::Result<Self> {
+        let mut message = message::Builder::new_default();
+        message
+            .init_root::<value::Builder<'_>>()
+            .init_list()
+            .set_as::<any_pointer::Owned>(list)?;
+        Ok(Self {
+            message,
+            ty: Type::list_of(list.element_type()),
+            len,
+        })
+    }
+
+    /// Copies the elements to the start of the list, leaving unset pointers unset.
+    fn copy_to(&self, list: &mut dynamic_list::Builder<'_>) -> capnp::Result<()> {
+        let value: value::Reader<'_> = self.message.get_root_as_reader()?;
+        let (value::List(pointer), dynamic_value::Reader::List(elements)) =
+            (value.which()?, dynamic_value::Reader::new(value, self.ty)?)
+        else {
+            return Err(capnp::Error::failed("Not a list".to_owned()));
+        };
+        // The dynamic API reads unset pointers as empty values, a list of pointers doesn't
+        let pointers = match elements.element_type().which() {
+            TypeVariant::Text | TypeVariant::Data | TypeVariant::List(_) => {
+                Some(pointer.get_as::<any_pointer_list::Reader<'_>>()?)
+            }
+            _ => None,
+        };
+        for index in 0..self.len {
+            if pointers.is_some_and(|pointers| pointers.get(index).is_null()) {
+                continue;
+            }
+            list.set(index, elements.get(index)?)?;
+        }
+        Ok(())
+    }
+}
@@ -1,23 +1,46 @@
+use std::marker::PhantomData;
+
 use capnp::{
-    dynamic_value,
-    introspect::TypeVariant,
-    schema::{EnumSchema, StructSchema},
+    dynamic_struct, dynamic_value,
+    introspect::{Type, TypeVariant},
+    schema::{EnumSchema, Field, StructSchema},
 };
-use serde::de::{DeserializeSeed, MapAccess, Unexpected, Visitor};
-use tracing::{error, trace};
+use serde::de::{DeserializeSeed, IgnoredAny, MapAccess, Unexpected, Visitor};
+use tracing::trace;
 
-use crate::types::enums::EnumVisitor;
+use crate::{
+    Options, UnknownFields,
+    annotations::{data_encoding, field_key},
+    error::{self, Segment, de_capnp},
+    limits,
+    mapping::{Entry, FieldEntry, Route, StructMapping},
+    schema::{display_name, field_name, is_group, is_union_member},
+    types::{
+        enums::EnumVisitor,
+        nullable::NullableSeed,
+        num::{NumVisitor, Number},
+    },
+};
 
 use super::{
-    STRUCT_ENUM_SCHEMA_FIELD_NAMES, dynamic_value_type_to_str, seq::SeqVisitor, type_variant_to_str,
+    bools::BoolVisitor,
+    content::{Buffer, Content, ContentDeserializer},
+    data::DataVisitor,
+    dynamic_value_type_to_str,
+    seq::{ListSlot, SeqVisitor},
+    text::TextVisitor,
+    type_variant_to_str, unsupported,
 };
 
-pub(crate) struct StructVisitor<'a> {
+pub(crate) struct StructVisitor<'a, 'o> {
     pub(crate) builder: capnp::dynamic_value::Builder<'a>,
-    pub(crate) ty: capnp::introspect::Type,
+    pub(crate) ty: Type,
+    /// The field if the struct is a group, which may carry annotations for it.
+    pub(crate) group: Option<Field>,
+    pub(crate) options: &'o Options,
 }
 
-impl<'a, 'de> DeserializeSeed<'de> for StructVisitor<'a> {
+impl<'a, 'de> DeserializeSeed<'de> for StructVisitor<'a, '_> {
     type Value = ();
 
     fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
@@ -26,81 +49,45 @@ impl<'a, 'de> DeserializeSeed<'de> for StructVisitor<'a> {
     {
         trace!("StructSeed::deserialize {:?}", self.ty);
         match self.ty.which() {
-            TypeVariant::Void => deserializer
-                .deserialize_unit(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Bool => deserializer
-                .deserialize_bool(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Int8 => deserializer
-                .deserialize_i8(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Int16 => deserializer
-                .deserialize_i16(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Int32 => deserializer
-                .deserialize_i32(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Int64 => deserializer
-                .deserialize_i64(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::UInt8 => deserializer
-                .deserialize_u8(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::UInt16 => deserializer
-                .deserialize_u16(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::UInt32 => deserializer
-                .deserialize_u32(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::UInt64 => deserializer
-                .deserialize_u64(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Float32 => deserializer
-                .deserialize_f32(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Float64 => deserializer
-                .deserialize_f64(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Text => todo!(),
-            TypeVariant::Data => todo!(),
+            TypeVariant::Void => deserializer.deserialize_unit(self)?,
+            TypeVariant::Bool => deserializer.deserialize_bool(self)?,
+            TypeVariant::Int8 => deserializer.deserialize_i8(self)?,
+            TypeVariant::Int16 => deserializer.deserialize_i16(self)?,
+            TypeVariant::Int32 => deserializer.deserialize_i32(self)?,
+            TypeVariant::Int64 => deserializer.deserialize_i64(self)?,
+            TypeVariant::UInt8 => deserializer.deserialize_u8(self)?,
+            TypeVariant::UInt16 => deserializer.deserialize_u16(self)?,
+            TypeVariant::UInt32 => deserializer.deserialize_u32(self)?,
+            TypeVariant::UInt64 => deserializer.deserialize_u64(self)?,
+            TypeVariant::Float32 => deserializer.deserialize_f32(self)?,
+            TypeVariant::Float64 => deserializer.deserialize_f64(self)?,
+            TypeVariant::Text
+            | TypeVariant::Data
+            | TypeVariant::Enum(_)
+            | TypeVariant::AnyPointer
+            | TypeVariant::Capability => {
+                return Err(unsupported(self.ty.which()));
+            }
             TypeVariant::Struct(raw_branded_struct_schema) => {
                 let schema = StructSchema::new(raw_branded_struct_schema);
-                let proto = schema.get_proto();
-                let field_names = STRUCT_ENUM_SCHEMA_FIELD_NAMES.insert(proto.get_id(), |_| {
-                    let fields = schema.get_fields().unwrap();
-                    fields
-                        .iter()
-                        .map(|field| field.get_proto().get_name().unwrap().to_str().unwrap())
-                        .collect::<Box<_>>()
-                });
+                let name = display_name(schema).map_err(de_capnp)?;
+                let mapping =
+                    StructMapping::get(schema, self.group, self.options).map_err(de_capnp)?;
+                let field_names = mapping.field_names();
+                // Groups are nested maps like structs, also when they're buffered
+                let _nested = limits::enter()?;
+
+                trace!("deserialize struct {name}, field names = {field_names:?}");
 
-                trace!(
-                    "deserialize struct {}, field names = {:?}",
-                    proto.get_display_name().unwrap().to_str().unwrap(),
-                    field_names
-                );
-
-                deserializer
-                    .deserialize_struct(
-                        proto.get_display_name().unwrap().to_str().unwrap(),
-                        field_names,
-                        self,
-                    )
-                    .inspect_err(|err| error!("{err}"))?;
+                deserializer.deserialize_struct(name, field_names, self)?;
             }
-            TypeVariant::List(_) => deserializer
-                .deserialize_seq(self)
-                .inspect_err(|err| error!("{err}"))?,
-            TypeVariant::Enum(_) => unimplemented!(),
-            TypeVariant::AnyPointer => unimplemented!(),
-            TypeVariant::Capability => unimplemented!(),
+            TypeVariant::List(_) => deserializer.deserialize_seq(self)?,
         }
         Ok(())
     }
 }
 
-impl<'a, 'de> Visitor<'de> for StructVisitor<'a> {
+impl<'a, 'de> Visitor<'de> for StructVisitor<'a, '_> {
     type Value = ();
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -124,230 +111,695 @@ impl<'a, 'de> Visitor<'de> for StructVisitor<'a> {
             ));
         };
         let schema = StructSchema::new(raw_schema);
-        trace!(
-            "StructSeed::visit_map {:?}",
-            schema
-                .get_proto()
-                .get_display_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-        );
+        trace!("StructSeed::visit_map {:?}", display_name(schema));
+
+        let mapping = StructMapping::get(schema, self.group, self.options).map_err(de_capnp)?;
+        let strict = !self.options.allow_conflicting_keys;
+        // The union members selected so far, either via the discriminator or via their value
+        let mut selected: Vec<Option<Field>> = vec![None; mapping.unions.len()];
+        // The keys seen so far in strict mode, by what they refer to
+        let mut seen: Vec<(Route, String)> = Vec::new();
+        // Whether the field entries were given, for `$required`
+        let mut provided = vec![false; mapping.entries.len()];
+        // The values of AnyPointer fields, which are read once their type is known
+        let mut pointers: Vec<Pointer> = Vec::new();
 
         loop {
             trace!("StructSeed::visit_map loop calling next_key");
-            let key = match map.next_key::<String>() {
-                Err(err) => {
-                    error!("{err}");
-                    return Err(err);
-                }
-                Ok(None) => break,
-                Ok(Some(key)) => key,
+            let Some(key) = map.next_key::<String>()? else {
+                break;
             };
-            let field = match schema.get_field_by_name(&key) {
-                Ok(field) => field,
-                Err(err) => {
-                    error!("{err}");
-                    return Err(serde::de::Error::custom(err));
+            let Some(route) = mapping.route(&key) else {
+                match self.options.unknown_fields {
+                    UnknownFields::Reject => {
+                        return Err(serde::de::Error::custom(format!("unknown field `{key}`")));
+                    }
+                    UnknownFields::Ignore => {}
+                    UnknownFields::Collect => error::unknown_field(key),
                 }
+                map.next_value::<IgnoredAny>()?;
+                continue;
             };
-            trace!(
-                "StructSeed::visit_map key = {key:?}, type = {:?}",
-                field.get_type()
-            );
-            match field.get_type().which() {
-                TypeVariant::List(inner_ty) => {
-                    let struct_builder = struct_builder.reborrow();
-                    map.next_value_seed(SeqVisitor {
-                        inner_ty,
-                        generator: |size| {
-                            let builder = struct_builder
-                                .initn(field, size)
-                                .inspect_err(|err| error!("{err}"))?;
-                            if let capnp::dynamic_value::Builder::List(list_builder) = builder {
-                                Ok(list_builder)
-                            } else {
-                                Err(capnp::Error::failed("Internal error".to_owned()))
-                            }
-                        },
-                    })?
-                }
-                TypeVariant::Text => {
-                    let text: String = map.next_value()?;
-                    let dynamic_value::Builder::Text(mut text_builder) = struct_builder
-                        .reborrow()
-                        .initn(field, text.len() as u32)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?
-                    else {
-                        return Err(serde::de::Error::custom("Internal error"));
+            if strict {
+                if let Some((_, previous)) = seen.iter().find(|(seen, _)| *seen == route) {
+                    let name = display_name(schema).map_err(de_capnp)?;
+                    let message = if *previous == key {
+                        format!("duplicate key `{key}` in `{name}`")
+                    } else {
+                        format!("the keys `{previous}` and `{key}` set the same field in `{name}`")
                     };
-                    text_builder.push_str(&text);
-                }
-                TypeVariant::Data => {
-                    let bytes: serde_bytes::ByteBuf =
-                        map.next_value().inspect_err(|err| error!("{err}"))?;
-                    if let Err(err) = struct_builder.set(field, dynamic_value::Reader::Data(&bytes))
-                    {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::Void => {
-                    map.next_value::<()>()?; // ignore value
-                }
-                TypeVariant::Bool => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Bool(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::Int8 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Int8(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::Int16 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Int16(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::Int32 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Int32(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::Int64 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Int64(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
+                    let ty = route_type(&mapping, &selected, route);
+                    return error::within(
+                        || Segment::Field(key),
+                        ty,
+                        || Err(serde::de::Error::custom(message)),
+                    );
                 }
-                TypeVariant::UInt8 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::UInt8(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
-                }
-                TypeVariant::UInt16 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::UInt16(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
-                    }
+                seen.push((route, key.clone()));
+            }
+            match route {
+                Route::Tag(union) => {
+                    let name: String = map.next_value().map_err(|err| {
+                        error::at(err, Segment::Field(key), TypeVariant::Text.into())
+                    })?;
+                    let scope = &mapping.unions[union];
+                    let field =
+                        mapping
+                            .member(union, &name)
+                            .map_err(de_capnp)?
+                            .ok_or_else(|| {
+                                serde::de::Error::custom(format!("`{name}` is not a union member"))
+                            })?;
+                    let mut builder = descend(
+                        struct_builder.reborrow(),
+                        &scope.path,
+                        &mapping,
+                        &mut selected,
+                        strict,
+                    )?;
+                    select(&mut builder, &mapping, &mut selected, union, field, strict)?;
                 }
-                TypeVariant::UInt32 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::UInt32(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
+                Route::Content(union) => {
+                    let scope = &mapping.unions[union];
+                    let Some(field) = selected[union] else {
+                        return Err(serde::de::Error::custom(format!(
+                            "the union discriminator `{}` has to precede `{key}`",
+                            scope.tag.as_deref().unwrap_or_default()
+                        )));
+                    };
+                    let mut builder = descend(
+                        struct_builder.reborrow(),
+                        &scope.path,
+                        &mapping,
+                        &mut selected,
+                        strict,
+                    )?;
+                    error::within(
+                        || Segment::Field(key),
+                        field.get_type(),
+                        || deserialize_field(&mut builder, field, &mut map, self.options),
+                    )?;
+                    if let Some(index) = mapping.entries.iter().position(|entry| {
+                        matches!(entry, Entry::Field(entry)
+                            if entry.union == Some(union)
+                                && entry.field.get_index() == field.get_index())
+                    }) {
+                        provided[index] = true;
                     }
                 }
-                TypeVariant::UInt64 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::UInt64(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
+                Route::Field(index) => {
+                    let Entry::Field(entry) = &mapping.entries[index] else {
+                        return Err(serde::de::Error::custom("Internal error"));
+                    };
+                    let FieldEntry {
+                        path, field, union, ..
+                    } = &**entry;
+                    trace!(
+                        "StructSeed::visit_map key = {key:?}, type = {:?}",
+                        field.get_type()
+                    );
+                    let mut builder = descend(
+                        struct_builder.reborrow(),
+                        path,
+                        &mapping,
+                        &mut selected,
+                        strict,
+                    )?;
+                    if let Some(union) = *union {
+                        track_member(&mapping, &mut selected, union, *field, strict)?;
                     }
-                }
-                TypeVariant::Float32 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Float32(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
+                    // The field naming the type of an AnyPointer may come later in the map
+                    if matches!(field.get_type().which(), TypeVariant::AnyPointer)
+                        && self
+                            .options
+                            .type_field(builder.get_schema(), *field)
+                            .map_err(de_capnp)?
+                            .is_some()
+                    {
+                        let (content, human_readable) = map.next_value_seed(Buffer)?;
+                        pointers.push(Pointer {
+                            key,
+                            path: path.clone(),
+                            field: *field,
+                            content,
+                            human_readable,
+                        });
+                    } else {
+                        error::within(
+                            || Segment::Field(key),
+                            field.get_type(),
+                            || deserialize_field(&mut builder, *field, &mut map, self.options),
+                        )?;
                     }
+                    provided[index] = true;
                 }
-                TypeVariant::Float64 => {
-                    if let Err(err) = struct_builder.set(
-                        field,
-                        dynamic_value::Reader::Float64(
-                            map.next_value().inspect_err(|err| error!("{err}"))?,
-                        ),
-                    ) {
-                        error!("{err}");
-                        return Err(serde::de::Error::custom(err));
+            }
+        }
+        for pointer in pointers {
+            let mut builder = descend(
+                struct_builder.reborrow(),
+                &pointer.path,
+                &mapping,
+                &mut selected,
+                strict,
+            )?;
+            let deserializer = ContentDeserializer::new(pointer.content, pointer.human_readable);
+            error::within(
+                || Segment::Field(pointer.key),
+                pointer.field.get_type(),
+                || {
+                    NullableSeed::new(AnyPointerSeed {
+                        struct_builder: builder.reborrow(),
+                        field: pointer.field,
+                        options: self.options,
+                    })
+                    .deserialize(deserializer)
+                },
+            )?
+            .map_or_else(
+                || clear_pointer(&mut builder, pointer.field, self.options),
+                Ok,
+            )?;
+        }
+        // A merged document only gives the fields that change
+        if self.options.merging {
+            return Ok(());
+        }
+        check_required(schema, &mapping, &provided, &selected)
+    }
+}
+
+/// Fails with the keys of all fields and unions required by annotations that weren't given. Fields
+/// below or of union members are only required if the member was selected.
+fn check_required<E>(
+    schema: StructSchema,
+    mapping: &StructMapping,
+    provided: &[bool],
+    selected: &[Option<Field>],
+) -> Result<(), E>
+where
+    E: serde::de::Error,
+{
+    let is_selected = |union: usize, field: Field| {
+        selected[union].is_some_and(|member| member.get_index() == field.get_index())
+    };
+    let is_reachable = |path: &[Field]| {
+        path.iter().enumerate().all(|(depth, &field)| {
+            !is_union_member(field)
+                || union_at(mapping, &path[..depth]).is_some_and(|union| is_selected(union, field))
+        })
+    };
+    let mut missing = Vec::new();
+    for (index, entry) in mapping.entries.iter().enumerate() {
+        let Entry::Field(entry) = entry else {
+            continue;
+        };
+        let FieldEntry {
+            key,
+            path,
+            field,
+            union,
+            required: true,
+        } = &**entry
+        else {
+            continue;
+        };
+        if provided[index] || !is_reachable(path) {
+            continue;
+        }
+        if let Some(union) = *union {
+            // Void members of tagged unions are fully given by the discriminator
+            if !is_selected(union, *field)
+                || (mapping.unions[union].tag.is_some()
+                    && matches!(field.get_type().which(), TypeVariant::Void))
+            {
+                continue;
+            }
+        }
+        missing.push(format!("`{key}`"));
+    }
+    for (index, scope) in mapping.unions.iter().enumerate() {
+        if !scope.required || selected[index].is_some() || !is_reachable(&scope.path) {
+            continue;
+        }
+        match &scope.tag {
+            Some(tag) => missing.push(format!("`{tag}`")),
+            None => {
+                let members = mapping.entries.iter().filter_map(|entry| match entry {
+                    Entry::Field(entry) if entry.union == Some(index) => {
+                        Some(format!("`{}`", entry.key))
                     }
+                    _ => None,
+                });
+                missing.push(format!("one of {}", members.collect::<Vec<_>>().join("/")));
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(E::custom(format!(
+        "missing required fields in `{}`: {}",
+        display_name(schema).map_err(de_capnp)?,
+        missing.join(", ")
+    )))
+}
+
+/// Follows the path of flattened groups and structs, selecting the union members along the way.
+fn descend<'a, E>(
+    mut builder: dynamic_struct::Builder<'a>,
+    path: &[Field],
+    mapping: &StructMapping,
+    selected: &mut [Option<Field>],
+    strict: bool,
+) -> Result<dynamic_struct::Builder<'a>, E>
+where
+    E: serde::de::Error,
+{
+    for (depth, &field) in path.iter().enumerate() {
+        if is_union_member(field) {
+            let union =
+                union_at(mapping, &path[..depth]).ok_or_else(|| E::custom("Internal error"))?;
+            select(&mut builder, mapping, selected, union, field, strict)?;
+        }
+        let dynamic_value::Builder::Struct(inner) = builder.get(field).map_err(de_capnp)? else {
+            return Err(E::custom("Internal error"));
+        };
+        builder = inner;
+    }
+    Ok(builder)
+}
+
+/// The union of the struct or group at the end of the path.
+/// The type of the value a key refers to, for errors about the key.
+fn route_type(mapping: &StructMapping, selected: &[Option<Field>], route: Route) -> Type {
+    let field = match route {
+        Route::Tag(_) => None,
+        Route::Content(union) => selected[union],
+        Route::Field(index) => match &mapping.entries[index] {
+            Entry::Field(entry) => Some(entry.field),
+            _ => None,
+        },
+    };
+    field.map_or(TypeVariant::Text.into(), |field| field.get_type())
+}
+
+fn union_at(mapping: &StructMapping, path: &[Field]) -> Option<usize> {
+    mapping
+        .unions
+        .iter()
+        .position(|scope| same_path(&scope.path, path))
+}
+
+fn same_path(a: &[Field], b: &[Field]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.get_index() == b.get_index())
+}
+
+/// Makes the given member the active one of its union, unless it already is.
+fn select<E>(
+    builder: &mut dynamic_struct::Builder<'_>,
+    mapping: &StructMapping,
+    selected: &mut [Option<Field>],
+    union: usize,
+    field: Field,
+    strict: bool,
+) -> Result<(), E>
+where
+    E: serde::de::Error,
+{
+    track_member(mapping, selected, union, field, strict)?;
+    let active = builder.which().map_err(de_capnp)?;
+    if active.is_none_or(|active| active.get_index() != field.get_index()) {
+        // Clearing selects the member with its default value, which is all a Void member needs.
+        // Other members are overwritten once their value is read.
+        builder.clear(field).map_err(de_capnp)?;
+    }
+    Ok(())
+}
+
+/// Remembers the member given for a union. In tagged unions, the discriminator and the value
+/// have to agree, and in strict mode, no other member may be given.
+fn track_member<E>(
+    mapping: &StructMapping,
+    selected: &mut [Option<Field>],
+    union: usize,
+    field: Field,
+    strict: bool,
+) -> Result<(), E>
+where
+    E: serde::de::Error,
+{
+    if (strict || mapping.unions[union].tag.is_some())
+        && let Some(member) = selected[union]
+    {
+        check_same_member(mapping, union, member, field)?;
+    }
+    selected[union] = Some(field);
+    Ok(())
+}
+
+/// Fails if two different members of the same union were given.
+fn check_same_member<E>(
+    mapping: &StructMapping,
+    union: usize,
+    selected: Field,
+    field: Field,
+) -> Result<(), E>
+where
+    E: serde::de::Error,
+{
+    if selected.get_index() == field.get_index() {
+        return Ok(());
+    }
+    Err(E::custom(format!(
+        "conflicting union members `{}` and `{}` in `{}`",
+        field_key(selected, mapping.naming.convention).map_err(de_capnp)?,
+        field_key(field, mapping.naming.convention).map_err(de_capnp)?,
+        display_name(mapping.unions[union].schema).map_err(de_capnp)?,
+    )))
+}
+
+/// Reads the value of the next map entry into the given field.
+fn deserialize_field<'de, A>(
+    struct_builder: &mut dynamic_struct::Builder<'_>,
+    field: Field,
+    map: &mut A,
+    options: &Options,
+) -> Result<(), A::Error>
+where
+    A: MapAccess<'de>,
+{
+    match field.get_type().which() {
+        TypeVariant::List(_) | TypeVariant::Text | TypeVariant::Data => {
+            deserialize_pointer(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Void => {
+            map.next_value::<()>()?;
+            // Setting a Void field selects it in case it's a union member
+            struct_builder
+                .set(field, dynamic_value::Reader::Void)
+                .map_err(de_capnp)?;
+        }
+        TypeVariant::Bool => {
+            map.next_value_seed(BoolVisitor::new(options, |b| {
+                struct_builder.set(field, dynamic_value::Reader::Bool(b))
+            }))?
+            .map_err(de_capnp)?;
+        }
+        TypeVariant::Int8 => {
+            deserialize_number::<i8, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Int16 => {
+            deserialize_number::<i16, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Int32 => {
+            deserialize_number::<i32, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Int64 => {
+            deserialize_number::<i64, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::UInt8 => {
+            deserialize_number::<u8, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::UInt16 => {
+            deserialize_number::<u16, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::UInt32 => {
+            deserialize_number::<u32, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::UInt64 => {
+            deserialize_number::<u64, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Float32 => {
+            deserialize_number::<f32, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Float64 => {
+            deserialize_number::<f64, _>(map, struct_builder, field, options)?;
+        }
+        TypeVariant::Enum(raw_schema) => {
+            let schema = EnumSchema::new(raw_schema);
+            map.next_value_seed(EnumVisitor::new(schema, options, |ordinal| {
+                struct_builder.set(
+                    field,
+                    dynamic_value::Reader::Enum(capnp::dynamic_value::Enum::new(ordinal, schema)),
+                )
+            }))?
+            .map_err(de_capnp)?;
+        }
+        TypeVariant::Struct(_) if is_group(field) => {
+            // Groups of a merged message keep their fields, unless they're a new union member
+            let builder = if options.merging && struct_builder.has(field).map_err(de_capnp)? {
+                struct_builder.reborrow().get(field)
+            } else {
+                struct_builder.reborrow().init(field)
+            }
+            .map_err(de_capnp)?;
+            let seed = StructVisitor {
+                builder,
+                ty: field.get_type(),
+                group: Some(field),
+                options,
+            };
+            map.next_value_seed(seed)?;
+        }
+        TypeVariant::Struct(_) => {
+            deserialize_pointer(map, struct_builder, field, options)?;
+        }
+        TypeVariant::AnyPointer | TypeVariant::Capability => {
+            // Without a type for the value, only an unset pointer can be represented
+            let value = map.next_value_seed(NullableSeed::new(PhantomData::<IgnoredAny>))?;
+            if value.is_some() {
+                return Err(unsupported(field.get_type().which()));
+            }
+            clear_pointer(struct_builder, field, options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the value of the next map entry into the given pointer field. Unit leaves the field
+/// unset, or clears it in a merged message, and selects it if it's a union member.
+fn deserialize_pointer<'de, A>(
+    map: &mut A,
+    struct_builder: &mut dynamic_struct::Builder<'_>,
+    field: Field,
+    options: &Options,
+) -> Result<(), A::Error>
+where
+    A: MapAccess<'de>,
+{
+    let value = map.next_value_seed(NullableSeed::new(PointerFieldSeed {
+        struct_builder: struct_builder.reborrow(),
+        field,
+        options,
+    }))?;
+    if value.is_none() {
+        clear_pointer(struct_builder, field, options)?;
+    }
+    Ok(())
+}
+
+/// Clears a pointer field for which unit was given. This selects a union member, and otherwise only
+/// matters in a merged message.
+fn clear_pointer<E>(
+    struct_builder: &mut dynamic_struct::Builder<'_>,
+    field: Field,
+    options: &Options,
+) -> Result<(), E>
+where
+    E: serde::de::Error,
+{
+    if is_union_member(field) || (options.merging && struct_builder.has(field).map_err(de_capnp)?) {
+        struct_builder.clear(field).map_err(de_capnp)?;
+    }
+    Ok(())
+}
+
+/// Reads the value of the next map entry into the given number field.
+fn deserialize_number<'de, N, A>(
+    map: &mut A,
+    struct_builder: &mut dynamic_struct::Builder<'_>,
+    field: Field,
+    options: &Options,
+) -> Result<(), A::Error>
+where
+    N: Number,
+    A: MapAccess<'de>,
+    for<'b> dynamic_value::Reader<'b>: From<N>,
+{
+    map.next_value_seed(NumVisitor::new(options, |num: N| {
+        struct_builder.set(field, num.into())
+    }))?
+    .map_err(de_capnp)
+}
+
+/// A list field of a struct.
+struct FieldSlot<'a> {
+    struct_builder: dynamic_struct::Builder<'a>,
+    field: Field,
+}
+
+impl ListSlot for FieldSlot<'_> {
+    fn init(&mut self, size: u32) -> capnp::Result<capnp::dynamic_list::Builder<'_>> {
+        let builder = self.struct_builder.reborrow().initn(self.field, size)?;
+        if let capnp::dynamic_value::Builder::List(list_builder) = builder {
+            Ok(list_builder)
+        } else {
+            Err(capnp::Error::failed("Internal error".to_owned()))
+        }
+    }
+
+    fn get(&mut self) -> capnp::Result<Option<capnp::dynamic_list::Reader<'_>>> {
+        if !self.struct_builder.has(self.field)? {
+            return Ok(None);
+        }
+        match self.struct_builder.reborrow_as_reader().get(self.field)? {
+            dynamic_value::Reader::List(list) => Ok(Some(list)),
+            _ => Err(capnp::Error::failed("Internal error".to_owned())),
+        }
+    }
+}
+
+/// Deserializes the value of a pointer field, initializing the pointer only once a value is present.
+struct PointerFieldSeed<'a, 'o> {
+    struct_builder: dynamic_struct::Builder<'a>,
+    field: Field,
+    options: &'o Options,
+}
+
+impl<'de> DeserializeSeed<'de> for PointerFieldSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let Self {
+            struct_builder,
+            field,
+            options,
+        } = self;
+        match field.get_type().which() {
+            TypeVariant::List(inner_ty) => {
+                let slot = FieldSlot {
+                    struct_builder,
+                    field,
+                };
+                SeqVisitor::new(inner_ty, options, slot).deserialize(deserializer)?;
+            }
+            TypeVariant::Text => {
+                let text = TextVisitor::new(options, str::to_owned).deserialize(deserializer)?;
+                let dynamic_value::Builder::Text(mut text_builder) = struct_builder
+                    .initn(field, text.len() as u32)
+                    .map_err(de_capnp)?
+                else {
+                    return Err(serde::de::Error::custom("Internal error"));
+                };
+                text_builder.push_str(&text);
+            }
+            TypeVariant::Data => {
+                let mut struct_builder = struct_builder;
+                let encoding = data_encoding(field)
+                    .map_err(de_capnp)?
+                    .unwrap_or(options.data_encoding);
+                DataVisitor::new(encoding, |bytes: &[u8]| {
+                    struct_builder.set(field, dynamic_value::Reader::Data(bytes))
+                })
+                .deserialize(deserializer)?
+                .map_err(de_capnp)?;
+            }
+            TypeVariant::Struct(raw) => {
+                // Structs of a merged message are merged as well
+                let builder = if options.merging && struct_builder.has(field).map_err(de_capnp)? {
+                    struct_builder.get(field)
+                } else {
+                    limits::allocate_struct(raw.into())?;
+                    struct_builder.init(field)
                 }
-                TypeVariant::Enum(raw_schema) => {
-                    let schema = EnumSchema::new(raw_schema);
-                    map.next_value_seed(EnumVisitor::new(schema, |enumerant| {
-                        struct_builder.set(
-                            field,
-                            dynamic_value::Reader::Enum(capnp::dynamic_value::Enum::new(
-                                enumerant.get_ordinal(),
-                                schema,
-                            )),
-                        )
-                    }))
-                    .inspect_err(|err| error!("{err}"))?
-                    .inspect_err(|err| error!("{err}"))
-                    .map_err(serde::de::Error::custom)?;
-                }
-                TypeVariant::Struct(_) => {
-                    let builder = struct_builder
-                        .reborrow()
-                        .init(field)
-                        .inspect_err(|err| error!("{err}"))
-                        .map_err(serde::de::Error::custom)?;
-                    let seed = StructVisitor {
-                        builder,
-                        ty: field.get_type(),
-                    };
-                    map.next_value_seed(seed)?;
-                }
-                TypeVariant::AnyPointer => unimplemented!(),
-                TypeVariant::Capability => unimplemented!(),
+                .map_err(de_capnp)?;
+                let seed = StructVisitor {
+                    builder,
+                    ty: field.get_type(),
+                    group: None,
+                    options,
+                };
+                seed.deserialize(deserializer)?;
             }
+            _ => return Err(serde::de::Error::custom("Internal error")),
         }
         Ok(())
     }
 }
+
+/// The buffered value of an `AnyPointer` field.
+struct Pointer {
+    key: String,
+    /// The path of flattened groups and structs to the field.
+    path: Box<[Field]>,
+    field: Field,
+    content: Content,
+    human_readable: bool,
+}
+
+/// Deserializes the value of an `AnyPointer` field as the registered struct type named by its
+/// type field. The value is replaced rather than merged, as its type may have changed.
+struct AnyPointerSeed<'a, 'o> {
+    struct_builder: dynamic_struct::Builder<'a>,
+    field: Field,
+    options: &'o Options,
+}
+
+impl<'de> DeserializeSeed<'de> for AnyPointerSeed<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let Self {
+            mut struct_builder,
+            field,
+            options,
+        } = self;
+        let Some(type_field) = options
+            .type_field(struct_builder.get_schema(), field)
+            .map_err(de_capnp)?
+        else {
+            return Err(unsupported(TypeVariant::AnyPointer));
+        };
+        let dynamic_value::Reader::UInt64(id) = struct_builder
+            .reborrow_as_reader()
+            .get(type_field)
+            .map_err(de_capnp)?
+        else {
+            return Err(serde::de::Error::custom("Internal error"));
+        };
+        // Schema IDs are never zero, so the type field wasn't given
+        if id == 0 {
+            let key = field_key(type_field, options.naming_convention).map_err(de_capnp)?;
+            return Err(serde::de::Error::custom(format!("`{key}` is missing")));
+        }
+        let registered = options
+            .type_registry
+            .lookup(id, field_name(field).map_err(de_capnp)?)
+            .map_err(de_capnp)?;
+        let TypeVariant::Struct(raw) = registered.ty.which() else {
+            return Err(serde::de::Error::custom("Internal error"));
+        };
+        limits::allocate_struct(raw.into())?;
+        // Clearing selects the field in case it's a union member
+        struct_builder.clear(field).map_err(de_capnp)?;
+        let dynamic_value::Builder::AnyPointer(pointer) =
+            struct_builder.get(field).map_err(de_capnp)?
+        else {
+            return Err(serde::de::Error::custom("Internal error"));
+        };
+        let seed = StructVisitor {
+            builder: registered.init(pointer).map_err(de_capnp)?.into(),
+            ty: registered.ty,
+            group: None,
+            options,
+        };
+        seed.deserialize(deserializer)
+    }
+}
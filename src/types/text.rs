@@ -1,13 +1,44 @@
-use serde::de::Visitor;
+use serde::de::{DeserializeSeed, Unexpected, Visitor};
 use tracing::trace;
 
-pub(super) struct TextVisitor<F> {
+use crate::{Options, limits};
+
+/// Reads text. In lenient mode, numbers and bools are accepted as well and written as text.
+pub(crate) struct TextVisitor<F> {
     setter: F,
+    lenient: bool,
 }
 
 impl<F> TextVisitor<F> {
-    pub(super) fn new(setter: F) -> Self {
-        Self { setter }
+    pub(crate) fn new(options: &Options, setter: F) -> Self {
+        Self {
+            setter,
+            lenient: options.lenient,
+        }
+    }
+}
+
+impl<F, Value> TextVisitor<F>
+where
+    F: FnOnce(&str) -> Value,
+{
+    fn set<E>(self, text: &str) -> Result<Value, E>
+    where
+        E: serde::de::Error,
+    {
+        limits::allocate_blob(text.len(), true)?;
+        Ok((self.setter)(text))
+    }
+
+    fn coerce<E>(self, text: impl FnOnce() -> String, unexpected: Unexpected) -> Result<Value, E>
+    where
+        E: serde::de::Error,
+    {
+        if self.lenient {
+            self.set(&text())
+        } else {
+            Err(E::invalid_type(unexpected, &self))
+        }
     }
 }
 
@@ -18,7 +49,11 @@ where
     type Value = Value;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "text")
+        if self.lenient {
+            write!(formatter, "text, a number or a bool")
+        } else {
+            write!(formatter, "text")
+        }
     }
 
     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
@@ -26,20 +61,75 @@ where
         E: serde::de::Error,
     {
         trace!("TextVisitor::visit_str {v:?}");
-        Ok((self.setter)(v))
+        self.set(v)
     }
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
         trace!("TextVisitor::visit_string {v:?}");
-        Ok((self.setter)(&v))
+        self.set(&v)
     }
     fn visit_borrowed_str<E>(self, v: &'de str) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
         trace!("TextVisitor::visit_borrowed_str {v:?}");
-        Ok((self.setter)(v))
+        self.set(v)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("TextVisitor::visit_bool {v:?}");
+        self.coerce(|| v.to_string(), Unexpected::Bool(v))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("TextVisitor::visit_u64 {v:?}");
+        self.coerce(|| v.to_string(), Unexpected::Unsigned(v))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("TextVisitor::visit_i64 {v:?}");
+        self.coerce(|| v.to_string(), Unexpected::Signed(v))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        trace!("TextVisitor::visit_f64 {v:?}");
+        // NaN and infinite values have no decimal representation
+        if !v.is_finite() {
+            return Err(E::invalid_type(Unexpected::Float(v), &self));
+        }
+        self.coerce(|| v.to_string(), Unexpected::Float(v))
+    }
+}
+
+impl<'de, F, Value> DeserializeSeed<'de> for TextVisitor<F>
+where
+    F: FnOnce(&str) -> Value,
+{
+    type Value = Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        // Other types can only be told apart from text by self-describing formats
+        if self.lenient {
+            deserializer.deserialize_any(self)
+        } else {
+            deserializer.deserialize_str(self)
+        }
     }
 }
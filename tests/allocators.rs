@@ -0,0 +1,62 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::{HeapAllocator, ScratchSpaceHeapAllocator, TypedBuilder};
+use capnp_serde::{CapnpSerdeBuilder, Options};
+use serde_json::json;
+
+use common::to_json;
+use schemas::example_capnp::complex;
+
+#[test]
+fn scratch_space_is_reused() {
+    let mut scratch = capnp::Word::allocate_zeroed_vec(64);
+    for i in 0..3 {
+        let value = json!({"b": format!("text {i}"), "g": [i, i + 1]});
+        let allocator =
+            ScratchSpaceHeapAllocator::new(capnp::Word::words_to_bytes_mut(&mut scratch));
+        let message = TypedBuilder::from(
+            CapnpSerdeBuilder::<complex::Owned, _>::deserialize_with_allocator(
+                &value,
+                allocator,
+                &Options::default(),
+            )
+            .unwrap(),
+        );
+        let output = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+        assert_eq!(output["b"], value["b"]);
+        assert_eq!(output["g"], value["g"]);
+    }
+}
+
+#[test]
+fn the_first_segment_is_sized_for_the_input() {
+    let json = serde_json::to_string(&json!({"g": vec![7; 4000]})).unwrap();
+    let mut deserializer = serde_json::Deserializer::from_str(&json);
+    let message = TypedBuilder::from(
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_input_len(
+            &mut deserializer,
+            json.len(),
+            &Options::default(),
+        )
+        .unwrap(),
+    );
+    assert_eq!(message.borrow_inner().get_segments_for_output().len(), 1);
+
+    // A smaller first segment can't hold the list
+    let allocator = HeapAllocator::new().first_segment_words(100);
+    let mut deserializer = serde_json::Deserializer::from_str(&json);
+    let message = TypedBuilder::from(
+        CapnpSerdeBuilder::<complex::Owned, _>::deserialize_with_allocator(
+            &mut deserializer,
+            allocator,
+            &Options::default(),
+        )
+        .unwrap(),
+    );
+    assert!(message.borrow_inner().get_segments_for_output().len() > 1);
+}
@@ -0,0 +1,175 @@
+mod common;
+mod schemas {
+    pub mod annotated_capnp {
+        include!(concat!(env!("OUT_DIR"), "/annotated_capnp.rs"));
+    }
+    #[allow(dead_code)]
+    pub mod json_capnp {
+        include!(concat!(env!("OUT_DIR"), "/capnp/compat/json_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeReader, Options};
+use serde_json::{Value, json};
+
+use common::{from_json, to_json};
+use schemas::annotated_capnp::{Color, annotated, collision};
+
+fn annotated_message() -> TypedBuilder<annotated::Owned> {
+    let mut message = TypedBuilder::<annotated::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_plain(7);
+    root.set_blob(&[1, 2, 3]);
+    root.set_hex_blob(&[0xde, 0xad]);
+    root.set_color(Color::Red);
+    root.reborrow().init_position().set_lat(1.5);
+    root.reborrow().get_position().set_lon(2.5);
+    let mut inner = root.reborrow().init_inner();
+    inner.set_x(3);
+    inner.set_y("y");
+    root.reborrow().init_choice().set_b("text");
+    root.set_second("zwei");
+    message
+}
+
+/// Serializes the example message.
+fn annotated_json() -> Value {
+    to_json(
+        annotated_message().get_root_as_reader().unwrap(),
+        &Options::default(),
+    )
+}
+
+/// Deserializes a document and serializes it again.
+fn round_trip(value: Value) -> Value {
+    let message = from_json::<annotated::Owned>(value, &Options::default()).unwrap();
+    to_json(message.get_root_as_reader().unwrap(), &Options::default())
+}
+
+#[test]
+fn annotated_structs_round_trip() {
+    let json = annotated_json();
+    assert_eq!(
+        json,
+        json!({
+            "renamed": 7,
+            "blob": "AQID",
+            "hexBlob": "dead",
+            "color": "RED",
+            "pos_lat": 1.5,
+            "pos_lon": 2.5,
+            "x": 3,
+            "y": "y",
+            "choice": {"type": "b", "value": "text"},
+            "kind": "zweite",
+            "zweite": "zwei",
+        })
+    );
+    assert_eq!(round_trip(json.clone()), json);
+}
+
+#[test]
+fn name() {
+    let message = from_json::<annotated::Owned>(
+        json!({"renamed": 7, "color": "RED", "kind": "zweite", "zweite": "zwei"}),
+        &Options::default(),
+    )
+    .unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_plain(), 7);
+    assert_eq!(reader.get_color().unwrap(), Color::Red);
+    assert!(matches!(reader.which(), Ok(annotated::Second(Ok(text))) if text == "zwei"));
+
+    for value in [
+        json!({"plain": 7}),
+        json!({"color": "red"}),
+        json!({"kind": "second", "second": "zwei"}),
+    ] {
+        assert!(
+            from_json::<annotated::Owned>(value.clone(), &Options::default()).is_err(),
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn flatten() {
+    let message = from_json::<annotated::Owned>(
+        json!({"pos_lat": 1.5, "pos_lon": 2.5, "x": 3, "y": "y"}),
+        &Options::default(),
+    )
+    .unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_position().get_lat(), 1.5);
+    assert_eq!(reader.get_position().get_lon(), 2.5);
+    assert_eq!(reader.get_inner().unwrap().get_x(), 3);
+    assert_eq!(reader.get_inner().unwrap().get_y().unwrap(), "y");
+
+    assert!(
+        from_json::<annotated::Owned>(json!({"position": {"lat": 1.5}}), &Options::default())
+            .is_err()
+    );
+}
+
+#[test]
+fn flatten_prefix_collisions_are_rejected() {
+    let message = TypedBuilder::<collision::Owned>::new_default();
+    let reader = CapnpSerdeReader::from(message.get_root_as_reader().unwrap());
+    let expected =
+        "the key `pos_lat` is used more than once in `annotated.capnp:Collision.position`";
+    let err = serde_json::to_value(&reader).unwrap_err();
+    assert!(err.to_string().contains(expected), "{err}");
+    let Err(err) = from_json::<collision::Owned>(json!({}), &Options::default()) else {
+        panic!("a colliding key was accepted");
+    };
+    assert!(err.to_string().contains(expected), "{err}");
+}
+
+#[test]
+fn discriminator() {
+    for (choice, value) in [
+        (
+            json!({"type": "a", "value": 5}),
+            json!({"kind": "first", "first": 1}),
+        ),
+        (json!({"type": "c"}), json!({"kind": "nothing"})),
+    ] {
+        let mut json = json!({"choice": choice});
+        json.as_object_mut()
+            .unwrap()
+            .extend(value.as_object().unwrap().clone());
+        let back = round_trip(json.clone());
+        assert_eq!(back["choice"], json["choice"]);
+        assert_eq!(back["kind"], json["kind"]);
+        assert_eq!(back.get("first"), json.get("first"));
+    }
+    let message = from_json::<annotated::Owned>(
+        json!({"choice": {"type": "c"}, "kind": "nothing"}),
+        &Options::default(),
+    )
+    .unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert!(matches!(
+        reader.get_choice().which(),
+        Ok(annotated::choice::C(()))
+    ));
+    assert!(matches!(reader.which(), Ok(annotated::Nothing(()))));
+}
+
+#[test]
+fn base64_and_hex() {
+    let message = from_json::<annotated::Owned>(
+        json!({"blob": "AQID", "hexBlob": "dead"}),
+        &Options::default(),
+    )
+    .unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_blob().unwrap(), [1, 2, 3]);
+    assert_eq!(reader.get_hex_blob().unwrap(), [0xde, 0xad]);
+
+    assert!(
+        from_json::<annotated::Owned>(json!({"hexBlob": "AQID"}), &Options::default()).is_err()
+    );
+    assert!(from_json::<annotated::Owned>(json!({"blob": "%"}), &Options::default()).is_err());
+}
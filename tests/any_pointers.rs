@@ -0,0 +1,90 @@
+mod common;
+mod schemas {
+    pub mod annotated_capnp {
+        include!(concat!(env!("OUT_DIR"), "/annotated_capnp.rs"));
+    }
+    #[allow(dead_code)]
+    pub mod json_capnp {
+        include!(concat!(env!("OUT_DIR"), "/capnp/compat/json_capnp.rs"));
+    }
+}
+
+use capnp::traits::HasTypeId;
+use capnp_serde::{Options, TypeRegistry};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::annotated_capnp::{envelope, inner};
+
+const INNER: u64 = <inner::Reader<'static> as HasTypeId>::TYPE_ID;
+
+fn registered() -> Options {
+    Options::default().type_registry(TypeRegistry::new().register::<inner::Owned>())
+}
+
+#[test]
+fn registered_types_round_trip() {
+    let value = json!({"payloadType": INNER, "payload": {"x": 1, "y": "a"}});
+    let message = from_json::<envelope::Owned>(value.clone(), &registered()).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    let payload: inner::Reader<'_> = root.get_payload().get_as().unwrap();
+    assert_eq!(payload.get_x(), 1);
+    assert_eq!(payload.get_y().unwrap(), "a");
+    assert_eq!(to_json(root, &registered()), value);
+}
+
+#[test]
+fn type_fields_may_follow_the_payload() {
+    let value = json!({"payload": {"x": 2, "y": "b"}, "payloadType": INNER});
+    let message = from_json::<envelope::Owned>(value.clone(), &registered()).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    assert_eq!(to_json(root, &registered()), value);
+}
+
+#[test]
+fn null_payloads_are_left_unset() {
+    let value = json!({"payloadType": INNER, "payload": null});
+    let message = from_json::<envelope::Owned>(value, &registered()).unwrap();
+    assert!(!message.get_root_as_reader().unwrap().has_payload());
+}
+
+#[test]
+fn unregistered_types_are_rejected() {
+    let value = json!({"payloadType": INNER, "payload": {"x": 1}});
+    let Err(err) = from_json::<envelope::Owned>(value, &Options::default()) else {
+        panic!("an unregistered type was accepted");
+    };
+    assert_eq!(err.path().to_string(), "payload");
+    assert!(
+        err.to_string().contains(&format!(
+            "the type {INNER:#018x} of `payload` isn't registered"
+        )),
+        "{err}"
+    );
+}
+
+#[test]
+fn missing_type_fields_are_reported() {
+    let value = json!({"payload": {"x": 1}});
+    let Err(err) = from_json::<envelope::Owned>(value, &registered()) else {
+        panic!("a payload without a type was accepted");
+    };
+    assert_eq!(
+        err.to_string(),
+        "payload (AnyPointer): `payloadType` is missing"
+    );
+}
+
+#[test]
+fn type_fields_can_be_set_with_the_options() {
+    let options = registered().any_pointer_type::<envelope::Owned>("untyped", "payloadType");
+    let value = json!({"payloadType": INNER, "untyped": {"x": 3, "y": "c"}});
+    let message = from_json::<envelope::Owned>(value.clone(), &options).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    let untyped: inner::Reader<'_> = root.get_untyped().get_as().unwrap();
+    assert_eq!(untyped.get_x(), 3);
+    assert_eq!(to_json(root, &options), value);
+
+    // Without the option, the field can only be null
+    assert!(from_json::<envelope::Owned>(value, &registered()).is_err());
+}
@@ -0,0 +1,53 @@
+#![allow(dead_code)]
+
+use capnp::{dynamic_value, message::TypedBuilder, traits::Owned};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, Options};
+use serde::de::value::SeqDeserializer;
+use serde_json::Value;
+
+/// Serializes a value to JSON.
+pub fn to_json<'a>(value: impl Into<dynamic_value::Reader<'a>>, options: &Options) -> Value {
+    let reader = CapnpSerdeReader::from(value.into()).with_options(options.clone());
+    serde_json::to_value(&reader).unwrap()
+}
+
+/// Deserializes a message from JSON.
+pub fn from_json<O>(value: Value, options: &Options) -> Result<TypedBuilder<O>, capnp_serde::Error>
+where
+    O: Owned + capnp::introspect::Introspect + 'static,
+    for<'a> O::Builder<'a>: Into<dynamic_value::Builder<'a>>,
+{
+    CapnpSerdeBuilder::<O>::deserialize_with_options(value, options).map(TypedBuilder::from)
+}
+
+/// The elements of a sequence, announcing a size hint that may be wrong, or none at all.
+pub struct Hinted {
+    elements: std::vec::IntoIter<Value>,
+    hint: Option<usize>,
+}
+
+impl Iterator for Hinted {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        self.elements.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.hint {
+            Some(hint) => (hint, Some(hint)),
+            None => (0, None),
+        }
+    }
+}
+
+/// A deserializer of a sequence with the given size hint.
+pub fn hinted_seq(
+    elements: Vec<Value>,
+    hint: Option<usize>,
+) -> SeqDeserializer<Hinted, serde_json::Error> {
+    SeqDeserializer::new(Hinted {
+        elements: elements.into_iter(),
+        hint,
+    })
+}
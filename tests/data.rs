@@ -0,0 +1,90 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, DataEncoding, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::complex;
+
+const DATA: &[u8] = &[0xde, 0xad, 0xbe, 0xef];
+
+fn data_message() -> TypedBuilder<complex::Owned> {
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    message.init_root().set_a(DATA);
+    message
+}
+
+#[test]
+fn encodings_round_trip() {
+    let message = data_message();
+    for (encoding, expected) in [
+        (DataEncoding::Auto, json!("3q2+7w==")),
+        (DataEncoding::Base64, json!("3q2+7w==")),
+        (DataEncoding::Base64Url, json!("3q2-7w")),
+        (DataEncoding::Hex, json!("deadbeef")),
+        (DataEncoding::Bytes, json!([0xde, 0xad, 0xbe, 0xef])),
+    ] {
+        let options = Options::default().data_encoding(encoding);
+        let json = to_json(message.get_root_as_reader().unwrap(), &options);
+        assert_eq!(json["a"], expected, "{encoding:?}");
+        let back = from_json::<complex::Owned>(json, &options).unwrap();
+        assert_eq!(back.get_root_as_reader().unwrap().get_a().unwrap(), DATA);
+    }
+}
+
+#[test]
+fn human_readable_formats_also_accept_byte_arrays() {
+    let message =
+        from_json::<complex::Owned>(json!({"a": [0xde, 0xad, 0xbe, 0xef]}), &Options::default())
+            .unwrap();
+    assert_eq!(message.get_root_as_reader().unwrap().get_a().unwrap(), DATA);
+}
+
+#[test]
+fn padding_is_optional() {
+    let message = from_json::<complex::Owned>(json!({"a": "3q2+7w"}), &Options::default()).unwrap();
+    assert_eq!(message.get_root_as_reader().unwrap().get_a().unwrap(), DATA);
+}
+
+#[test]
+fn invalid_strings_are_rejected() {
+    let options = Options::default().data_encoding(DataEncoding::Hex);
+    assert!(from_json::<complex::Owned>(json!({"a": "xyz"}), &options).is_err());
+}
+
+#[test]
+fn compact_formats_use_bytes_by_default() {
+    let message = data_message();
+    let reader = CapnpSerdeReader::from(message.get_root_as_reader().unwrap());
+    let bytes = rmp_serde::to_vec(&reader).unwrap();
+    let back: CapnpSerdeBuilder<complex::Owned> = rmp_serde::from_slice(&bytes).unwrap();
+    let back = TypedBuilder::from(back);
+    assert_eq!(back.get_root_as_reader().unwrap().get_a().unwrap(), DATA);
+
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&reader, &mut cbor).unwrap();
+    let value: ciborium::Value = ciborium::from_reader(cbor.as_slice()).unwrap();
+    let entries = value.as_map().unwrap();
+    let (_, a) = entries
+        .iter()
+        .find(|(key, _)| key.as_text() == Some("a"))
+        .unwrap();
+    assert_eq!(a.as_bytes().unwrap(), DATA);
+}
+
+#[test]
+fn yaml_round_trips() {
+    let message = data_message();
+    let reader = CapnpSerdeReader::from(message.get_root_as_reader().unwrap());
+    let yaml = serde_yml::to_string(&reader).unwrap();
+    assert!(yaml.contains("a: '3q2+7w=='"), "{yaml}");
+    let back: CapnpSerdeBuilder<complex::Owned> = serde_yml::from_str(&yaml).unwrap();
+    let back = TypedBuilder::from(back);
+    assert_eq!(back.get_root_as_reader().unwrap().get_a().unwrap(), DATA);
+}
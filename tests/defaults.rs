@@ -0,0 +1,87 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{dynamic_struct, dynamic_value, message::TypedBuilder, schema_capnp::value};
+use capnp_serde::Options;
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::{basic, complex};
+
+#[test]
+fn primitive_fields_are_always_written() {
+    let message = TypedBuilder::<complex::Owned>::new_default();
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &Options::default()),
+        json!({"c": {"d": 0, "e": false}, "default": 12, "i": "a"})
+    );
+}
+
+#[test]
+fn only_pointer_fields_are_affected() {
+    let message = TypedBuilder::<basic::Owned>::new_default();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(
+        to_json(reader, &Options::default().emit_defaults(true)),
+        to_json(reader, &Options::default())
+    );
+
+    // An AnyPointer has no default value to write
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().init_any_pointer();
+    let options = Options::default().emit_defaults(true);
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &options),
+        json!({})
+    );
+}
+
+#[test]
+fn unset_pointer_fields_are_written_with_their_default() {
+    let options = Options::default().emit_defaults(true);
+    let message = TypedBuilder::<complex::Owned>::new_default();
+    let expected = json!({
+        "a": "",
+        "b": "",
+        "c": {"d": 0, "e": false},
+        "default": 12,
+        "f": [],
+        "g": [],
+        "h": [],
+        "i": "a",
+        "j": [],
+        "shouldbenull": {"a": 0, "b": false},
+    });
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &options),
+        expected
+    );
+    let back = from_json::<complex::Owned>(expected.clone(), &options).unwrap();
+    assert_eq!(
+        to_json(back.get_root_as_reader().unwrap(), &options),
+        expected
+    );
+}
+
+#[test]
+fn active_union_members_are_written_with_their_default() {
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    let mut root =
+        dynamic_value::Builder::from(message.init_root()).downcast::<dynamic_struct::Builder>();
+    let text = root.get_schema().get_field_by_name("text").unwrap();
+    root.clear(text).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(to_json(reader, &Options::default()), json!({}));
+
+    let options = Options::default().emit_defaults(true);
+    assert_eq!(to_json(reader, &options), json!({"text": ""}));
+    let back = from_json::<value::Owned>(json!({"text": ""}), &options).unwrap();
+    assert!(matches!(
+        back.get_root_as_reader().unwrap().which(),
+        Ok(value::Text(_))
+    ));
+}
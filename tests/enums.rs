@@ -0,0 +1,149 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{
+    dynamic_struct,
+    dynamic_value::{self, Enum},
+    introspect::TypeVariant,
+    message::TypedBuilder,
+};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, EnumRepresentation, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::{Foo, complex};
+
+fn enum_message() -> TypedBuilder<complex::Owned> {
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_i(Foo::C);
+    let mut list = root.init_j(2);
+    list.set(0, Foo::A);
+    list.set(1, Foo::D);
+    message
+}
+
+#[test]
+fn names_and_ordinals_round_trip() {
+    let message = enum_message();
+    for (representation, i, j) in [
+        (EnumRepresentation::Name, json!("c"), json!(["a", "d"])),
+        (EnumRepresentation::Ordinal, json!(2), json!([0, 3])),
+        (
+            EnumRepresentation::NameIfHumanReadable,
+            json!("c"),
+            json!(["a", "d"]),
+        ),
+    ] {
+        let options = Options::default().enum_representation(representation);
+        let json = to_json(message.get_root_as_reader().unwrap(), &options);
+        assert_eq!((&json["i"], &json["j"]), (&i, &j), "{representation:?}");
+        let back = from_json::<complex::Owned>(json, &options).unwrap();
+        let reader = back.get_root_as_reader().unwrap();
+        assert_eq!(reader.get_i().unwrap(), Foo::C);
+        assert_eq!(reader.get_j().unwrap().get(1).unwrap(), Foo::D);
+    }
+}
+
+#[test]
+fn ordinals_are_accepted_with_any_representation() {
+    let message =
+        from_json::<complex::Owned>(json!({"i": 3, "j": [1, "c"]}), &Options::default()).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_i().unwrap(), Foo::D);
+    assert_eq!(reader.get_j().unwrap().get(0).unwrap(), Foo::B);
+    assert_eq!(reader.get_j().unwrap().get(1).unwrap(), Foo::C);
+}
+
+#[test]
+fn unknown_names_are_rejected() {
+    for value in [json!({"i": "e"}), json!({"i": -1}), json!({"i": true})] {
+        assert!(
+            from_json::<complex::Owned>(value.clone(), &Options::default()).is_err(),
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn compact_formats_use_ordinals_if_not_human_readable() {
+    let message = enum_message();
+    let options = Options::default().enum_representation(EnumRepresentation::NameIfHumanReadable);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options.clone());
+    let bytes = rmp_serde::to_vec(&reader).unwrap();
+    let value: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(value["i"], json!(2));
+
+    let back = CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(
+        &mut rmp_serde::Deserializer::new(bytes.as_slice()),
+        &options,
+    )
+    .unwrap();
+    let back = TypedBuilder::from(back);
+    assert_eq!(back.get_root_as_reader().unwrap().get_i().unwrap(), Foo::C);
+}
+
+#[test]
+fn compact_formats_read_names() {
+    let message = enum_message();
+    let reader = CapnpSerdeReader::from(message.get_root_as_reader().unwrap());
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&reader, &mut cbor).unwrap();
+    for back in [
+        rmp_serde::from_slice::<CapnpSerdeBuilder<complex::Owned>>(
+            &rmp_serde::to_vec(&reader).unwrap(),
+        )
+        .unwrap(),
+        ciborium::from_reader::<CapnpSerdeBuilder<complex::Owned>, _>(cbor.as_slice()).unwrap(),
+    ] {
+        let back = TypedBuilder::from(back);
+        let root = back.get_root_as_reader().unwrap();
+        assert_eq!(root.get_i().unwrap(), Foo::C);
+        let j = root.get_j().unwrap();
+        assert_eq!(j.get(0).unwrap(), Foo::A);
+        assert_eq!(j.get(1).unwrap(), Foo::D);
+    }
+}
+
+#[test]
+fn unknown_ordinals_round_trip() {
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    let mut root =
+        dynamic_value::Builder::from(message.init_root()).downcast::<dynamic_struct::Builder>();
+    let TypeVariant::Enum(schema) = root
+        .get_schema()
+        .get_field_by_name("i")
+        .unwrap()
+        .get_type()
+        .which()
+    else {
+        panic!("`i` isn't an enum");
+    };
+    root.set_named(
+        "i",
+        dynamic_value::Reader::Enum(Enum::new(9, schema.into())),
+    )
+    .unwrap();
+    let json = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!(json["i"], json!(9));
+
+    let back =
+        from_json::<complex::Owned>(json!({"i": 9, "j": ["b", 7]}), &Options::default()).unwrap();
+    let json = to_json(back.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!((&json["i"], &json["j"]), (&json!(9), &json!(["b", 7])));
+
+    // Compact formats keep them as well
+    let reader = CapnpSerdeReader::from(back.get_root_as_reader().unwrap());
+    let mut cbor = Vec::new();
+    ciborium::into_writer(&reader, &mut cbor).unwrap();
+    let back = TypedBuilder::from(
+        ciborium::from_reader::<CapnpSerdeBuilder<complex::Owned>, _>(cbor.as_slice()).unwrap(),
+    );
+    let json = to_json(back.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!((&json["i"], &json["j"]), (&json!(9), &json!(["b", 7])));
+}
@@ -0,0 +1,120 @@
+mod common;
+
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{
+    any_pointer, any_pointer_list,
+    message::TypedBuilder,
+    schema_capnp::{node, value},
+};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, Cause, NonFiniteFloats, Options};
+use serde_json::json;
+
+use common::from_json;
+use schemas::example_capnp::complex;
+
+#[test]
+fn unsupported_types_are_errors() {
+    let options = Options::default();
+    let Err(err) = from_json::<value::Owned>(json!({"anyPointer": 5}), &options) else {
+        panic!("an AnyPointer value was accepted");
+    };
+    assert!(err.to_string().contains("AnyPointer"), "{err}");
+    assert!(from_json::<any_pointer::Owned>(json!({"a": 1}), &options).is_err());
+    assert!(from_json::<any_pointer_list::Owned>(json!([1]), &options).is_err());
+}
+
+#[test]
+fn malformed_documents_are_errors() {
+    let options = Options::default();
+    for value in [
+        json!([]),
+        json!(5),
+        json!({"text": 5}),
+        json!({"data": {}}),
+        json!({"enum": "x"}),
+        json!({"list": 5}),
+        json!({"struct": [1]}),
+        json!({"int16": 1e10}),
+        json!({"bool": "x"}),
+    ] {
+        assert!(
+            from_json::<value::Owned>(value.clone(), &options).is_err(),
+            "{value}"
+        );
+    }
+}
+
+#[test]
+fn errors_locate_the_value() {
+    let value = json!({"h": [{"a": 1}, {"a": 2}, {"b": 5}]});
+    let Err(err) = from_json::<complex::Owned>(value, &Options::default()) else {
+        panic!("a number was accepted for Bool");
+    };
+    assert_eq!(err.path().to_string(), "h[2].b");
+    assert_eq!(err.path().to_pointer(), "/h/2/b");
+    assert_eq!(err.expected(), Some("Bool"));
+    assert!(matches!(err.cause(), Cause::Format(_)));
+    assert!(err.to_string().starts_with("h[2].b (Bool): "), "{err}");
+}
+
+#[test]
+fn serialization_errors_locate_the_value() {
+    let mut message = TypedBuilder::<node::Owned>::new_default();
+    let mut annotations = message.init_root().init_annotations(2);
+    annotations
+        .reborrow()
+        .get(1)
+        .init_value()
+        .set_float64(f64::NAN);
+    let options = Options::default().non_finite_floats(NonFiniteFloats::Error);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options);
+    let err = reader
+        .try_serialize(serde_json::value::Serializer)
+        .unwrap_err();
+    assert_eq!(err.path().to_string(), "annotations[1].value.float64");
+    assert_eq!(err.expected(), Some("Float64"));
+    assert_eq!(
+        err.to_string(),
+        "annotations[1].value.float64 (Float64): the float NaN can't be serialized"
+    );
+}
+
+#[test]
+fn failed_serde_conversions_leave_no_trace() {
+    for _ in 0..3 {
+        let bytes = rmp_serde::to_vec(&json!({"nestedNodes": [{"name": 5}]})).unwrap();
+        assert!(rmp_serde::from_slice::<CapnpSerdeBuilder<node::Owned>>(&bytes).is_err());
+        assert!(
+            serde_json::from_value::<CapnpSerdeBuilder<value::Owned>>(json!({"int8": 300}))
+                .is_err()
+        );
+    }
+    let Err(err) = from_json::<value::Owned>(json!({"text": 5}), &Options::default()) else {
+        panic!("a number was accepted for Text");
+    };
+    assert_eq!(err.path().to_string(), "text");
+    assert_eq!(err.expected(), Some("Text"));
+    assert!(matches!(err.cause(), Cause::Format(_)));
+}
+
+#[test]
+fn failed_serializations_leave_no_trace() {
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().set_float64(f64::NAN);
+    let options = Options::default().non_finite_floats(NonFiniteFloats::Error);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options);
+    for _ in 0..3 {
+        assert!(serde_json::to_value(&reader).is_err());
+    }
+    let err = reader
+        .try_serialize(serde_json::value::Serializer)
+        .unwrap_err();
+    assert_eq!(err.path().to_string(), "float64");
+}
@@ -0,0 +1,75 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeReader, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::{complex, nested, unions};
+
+#[test]
+fn groups() {
+    let options = Options::default().flatten_groups(true);
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    let mut root = message.init_root();
+    root.reborrow().init_c().set_d(14);
+    root.set_b("text");
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(
+        json,
+        json!({"b": "text", "d": 14, "e": false, "default": 12, "i": "a"})
+    );
+    let back = from_json::<complex::Owned>(json.clone(), &options).unwrap();
+    assert_eq!(back.get_root_as_reader().unwrap().get_c().get_d(), 14);
+    assert_eq!(to_json(back.get_root_as_reader().unwrap(), &options), json);
+}
+
+#[test]
+fn named_unions_stay_nested() {
+    let options = Options::default().flatten_groups(true);
+    let mut message = TypedBuilder::<unions::Owned>::new_default();
+    message.init_root().init_named().set_a(42);
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(json, json!({"named": {"a": 42}, "d": 0}));
+}
+
+#[test]
+fn fields_with_a_prefix() {
+    let options = Options::default().flatten_field::<nested::Owned>("b", "b_");
+    let mut message = TypedBuilder::<nested::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_a(1);
+    root.reborrow().init_b().set_a(2);
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(json, json!({"a": 1, "b_a": 2, "b_b": false}));
+    let back = from_json::<nested::Owned>(json.clone(), &options).unwrap();
+    let reader = back.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_b().unwrap().get_a(), 2);
+    assert_eq!(to_json(reader, &options), json);
+
+    assert!(from_json::<nested::Owned>(json!({"b": {"a": 2}}), &options).is_err());
+}
+
+#[test]
+fn unset_flattened_structs_are_left_out() {
+    let options = Options::default().flatten_field::<nested::Owned>("b", "b_");
+    let mut message = TypedBuilder::<nested::Owned>::new_default();
+    message.init_root().set_a(1);
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(json, json!({"a": 1}));
+}
+
+#[test]
+fn key_collisions_are_rejected() {
+    let options = Options::default().flatten_field::<complex::Owned>("shouldbenull", "");
+    let message = TypedBuilder::<complex::Owned>::new_default();
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options.clone());
+    assert!(serde_json::to_value(&reader).is_err());
+    assert!(from_json::<complex::Owned>(json!({}), &options).is_err());
+}
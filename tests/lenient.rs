@@ -0,0 +1,93 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::schema_capnp::value;
+use capnp_serde::Options;
+use serde_json::{Value, json};
+
+use common::{from_json, to_json};
+use schemas::example_capnp::complex;
+
+fn lenient() -> Options {
+    Options::default().lenient(true)
+}
+
+/// Deserializes a `Value` leniently and serializes it again.
+fn coerce(json: Value) -> Value {
+    let message = from_json::<value::Owned>(json, &lenient()).unwrap();
+    to_json(message.get_root_as_reader().unwrap(), &Options::default())
+}
+
+#[test]
+fn numbers() {
+    assert_eq!(coerce(json!({"uint32": "42"})), json!({"uint32": 42}));
+    assert_eq!(coerce(json!({"int8": 3.0})), json!({"int8": 3}));
+    assert_eq!(coerce(json!({"int8": "3.0"})), json!({"int8": 3}));
+    assert_eq!(coerce(json!({"float64": "1.5"})), json!({"float64": 1.5}));
+}
+
+#[test]
+fn booleans() {
+    for (input, expected) in [
+        (json!("true"), true),
+        (json!("false"), false),
+        (json!(1), true),
+        (json!(0), false),
+    ] {
+        assert_eq!(coerce(json!({"bool": input})), json!({"bool": expected}));
+    }
+}
+
+#[test]
+fn text() {
+    assert_eq!(coerce(json!({"text": 5})), json!({"text": "5"}));
+    assert_eq!(coerce(json!({"text": -1.5})), json!({"text": "-1.5"}));
+    assert_eq!(coerce(json!({"text": true})), json!({"text": "true"}));
+}
+
+#[test]
+fn single_values_for_lists() {
+    let message =
+        from_json::<complex::Owned>(json!({"f": "one", "g": 7, "h": {"a": 1}}), &lenient())
+            .unwrap();
+    let json = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!(json["f"], json!(["one"]));
+    assert_eq!(json["g"], json!([7]));
+    assert_eq!(json["h"], json!([{"a": 1, "b": false}]));
+}
+
+#[test]
+fn ambiguous_values_are_rejected() {
+    for json in [
+        json!({"int32": "3.5"}),
+        json!({"int32": 3.5}),
+        json!({"bool": "yes"}),
+        json!({"bool": 2}),
+        json!({"text": [1]}),
+        json!({"uint8": "-1"}),
+    ] {
+        assert!(
+            from_json::<value::Owned>(json.clone(), &lenient()).is_err(),
+            "{json}"
+        );
+    }
+}
+
+#[test]
+fn nothing_is_coerced_by_default() {
+    for json in [
+        json!({"bool": "true"}),
+        json!({"text": 5}),
+        json!({"float64": "1.5"}),
+    ] {
+        assert!(
+            from_json::<value::Owned>(json.clone(), &Options::default()).is_err(),
+            "{json}"
+        );
+    }
+    assert!(from_json::<complex::Owned>(json!({"g": 7}), &Options::default()).is_err());
+}
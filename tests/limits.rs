@@ -0,0 +1,163 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{message::TypedBuilder, primitive_list, struct_list};
+use capnp_serde::{CapnpSerdeBuilder, Options};
+use serde_json::json;
+
+use common::{from_json, hinted_seq};
+use schemas::example_capnp::{complex, nested};
+
+fn error_message<T>(result: Result<T, capnp_serde::Error>) -> String {
+    match result {
+        Ok(_) => panic!("the limit wasn't enforced"),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+fn max_depth() {
+    let value = json!({"b": {"a": 1}});
+    let options = Options::default().max_depth(Some(1));
+    let message = error_message(from_json::<nested::Owned>(value.clone(), &options));
+    assert_eq!(
+        message,
+        "b (example.capnp:Basic): the nesting depth exceeds the limit of 1"
+    );
+    assert!(from_json::<nested::Owned>(value, &Options::default().max_depth(Some(2))).is_ok());
+}
+
+#[test]
+fn groups_count_toward_the_depth() {
+    let json = r#"[{"c": {"d": 1}}]"#;
+    for max_depth in [2, 3] {
+        let options = Options::default().max_depth(Some(max_depth));
+        // Without a size hint, the elements are buffered before they're deserialized
+        let direct = from_json::<struct_list::Owned<complex::Owned>>(
+            serde_json::from_str(json).unwrap(),
+            &options,
+        );
+        let buffered =
+            CapnpSerdeBuilder::<struct_list::Owned<complex::Owned>>::deserialize_with_options(
+                &mut serde_json::Deserializer::from_str(json),
+                &options,
+            );
+        assert_eq!(direct.is_ok(), max_depth == 3);
+        assert_eq!(buffered.is_ok(), max_depth == 3);
+    }
+}
+
+#[test]
+fn max_list_length() {
+    let value = json!({"g": [1, 2, 3]});
+    let options = Options::default().max_list_length(Some(2));
+    let message = error_message(from_json::<complex::Owned>(value.clone(), &options));
+    assert_eq!(
+        message,
+        "g[2] (UInt16): the list length of 3 exceeds the limit of 2"
+    );
+    // Without a size hint, the elements are counted while they're read as well
+    let mut deserializer = serde_json::Deserializer::from_str(r#"{"g": [1, 2, 3]}"#);
+    let result =
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(&mut deserializer, &options);
+    assert_eq!(
+        error_message(result),
+        "g[2] (UInt16): the list length of 3 exceeds the limit of 2 at line 1 column 15"
+    );
+    assert!(
+        from_json::<complex::Owned>(value, &Options::default().max_list_length(Some(3))).is_ok()
+    );
+}
+
+#[test]
+fn size_hints_beyond_the_limits_are_not_trusted() {
+    // A MessagePack map with `g`, announcing an array of 2^32 - 1 elements that never come
+    let mut bytes = vec![0x81, 0xa1, b'g', 0xdd];
+    bytes.extend_from_slice(&u32::MAX.to_be_bytes());
+    let options = Options::default().max_list_length(Some(10));
+    let mut deserializer = rmp_serde::Deserializer::from_read_ref(&bytes);
+    let result =
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(&mut deserializer, &options);
+    assert!(result.is_err());
+}
+
+#[test]
+fn huge_size_hints_are_not_trusted_without_limits() {
+    // A CBOR map with `g`, announcing an array of 2^30 elements, more than a list can hold
+    let mut bytes = vec![0xa1, 0x61, b'g', 0x9a];
+    bytes.extend_from_slice(&(1u32 << 30).to_be_bytes());
+    let result = ciborium::from_reader::<CapnpSerdeBuilder<complex::Owned>, _>(&bytes[..]);
+    assert!(result.is_err());
+
+    // A hint that is wrong by far only costs a list of a few elements
+    let deserializer = hinted_seq(vec![json!(1), json!(2)], Some(1 << 31));
+    let message = CapnpSerdeBuilder::<primitive_list::Owned<u16>>::deserialize_with_options(
+        deserializer,
+        &Options::default(),
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let root = message.get_root_as_reader().unwrap();
+    assert_eq!(root.iter().collect::<Vec<_>>(), [1, 2]);
+}
+
+#[test]
+fn max_blob_size() {
+    let options = Options::default().max_blob_size(Some(4));
+    let message = error_message(from_json::<complex::Owned>(json!({"b": "hello"}), &options));
+    assert_eq!(
+        message,
+        "b (Text): the text size of 5 bytes exceeds the limit of 4"
+    );
+    let message = error_message(from_json::<complex::Owned>(
+        json!({"a": "AQIDBAU="}),
+        &options,
+    ));
+    assert_eq!(
+        message,
+        "a (Data): the data size of 5 bytes exceeds the limit of 4"
+    );
+    let message = error_message(from_json::<complex::Owned>(
+        json!({"f": ["abc", "hello"]}),
+        &options,
+    ));
+    assert_eq!(
+        message,
+        "f[1] (Text): the text size of 5 bytes exceeds the limit of 4"
+    );
+    assert!(from_json::<complex::Owned>(json!({"b": "hell", "a": "AQIDBA=="}), &options).is_ok());
+}
+
+#[test]
+fn max_message_words() {
+    let value = json!({"g": vec![7; 400]});
+    let options = Options::default().max_message_words(Some(100));
+    let message = error_message(from_json::<complex::Owned>(value.clone(), &options));
+    assert_eq!(
+        message,
+        "g (List(UInt16)): the message exceeds the limit of 100 words"
+    );
+    assert!(
+        from_json::<complex::Owned>(value, &Options::default().max_message_words(Some(200)))
+            .is_ok()
+    );
+}
+
+#[test]
+fn the_budget_is_reset_between_deserializations() {
+    // Each message takes more than half of the words
+    let value = json!({"g": vec![7; 300]});
+    let options = Options::default().max_message_words(Some(150));
+    for _ in 0..3 {
+        assert!(from_json::<complex::Owned>(value.clone(), &options).is_ok());
+    }
+    let deep = json!({"b": {"a": 1}});
+    assert!(
+        from_json::<nested::Owned>(deep.clone(), &Options::default().max_depth(Some(1))).is_err()
+    );
+    assert!(from_json::<nested::Owned>(deep, &Options::default().max_depth(Some(2))).is_ok());
+}
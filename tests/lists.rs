@@ -0,0 +1,164 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use bincode::Options as _;
+use capnp::{
+    list_list,
+    message::{self, TypedBuilder},
+    primitive_list,
+    schema_capnp::annotation,
+    struct_list, text_list,
+    traits::Owned,
+};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, Options};
+use serde_json::{Value, json};
+
+use common::{hinted_seq, to_json};
+use schemas::example_capnp::{basic, complex};
+
+/// Deserializes a list root from the elements with the given size hint and serializes it again.
+fn round_trip<O>(elements: &Value, hint: Option<usize>) -> Value
+where
+    O: Owned + capnp::introspect::Introspect + 'static,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+    for<'a> O::Reader<'a>: Into<capnp::dynamic_value::Reader<'a>>,
+{
+    let options = Options::default().null_unset_pointers(true);
+    let deserializer = hinted_seq(elements.as_array().unwrap().clone(), hint);
+    let message = TypedBuilder::from(
+        CapnpSerdeBuilder::<O>::deserialize_with_options(deserializer, &options).unwrap(),
+    );
+    to_json(message.get_root_as_reader().unwrap(), &options)
+}
+
+/// The size hints to try for a list of the given length: none, too small, right and too large.
+fn hints(len: usize) -> [Option<usize>; 5] {
+    [None, Some(0), Some(len - 1), Some(len), Some(len + 3)]
+}
+
+#[test]
+fn text_lists_with_any_size_hint() {
+    let elements = json!(["a", null, "", "d"]);
+    for hint in hints(4) {
+        assert_eq!(
+            round_trip::<text_list::Owned>(&elements, hint),
+            elements,
+            "{hint:?}"
+        );
+    }
+}
+
+#[test]
+fn struct_lists_with_any_size_hint() {
+    let elements = json!([{"a": 1, "b": true}, {"a": 2, "b": false}, {"a": 3, "b": true}]);
+    for hint in hints(3) {
+        assert_eq!(
+            round_trip::<struct_list::Owned<basic::Owned>>(&elements, hint),
+            elements,
+            "{hint:?}"
+        );
+    }
+}
+
+#[test]
+fn structs_with_pointers_with_any_size_hint() {
+    let elements = json!([
+        {"id": 1, "brand": null, "value": {"text": "a"}},
+        {"id": 2, "brand": {"scopes": []}, "value": {"anyPointer": null}},
+        {"id": 3, "brand": null, "value": {"int8": 4}},
+    ]);
+    for hint in hints(3) {
+        assert_eq!(
+            round_trip::<struct_list::Owned<annotation::Owned>>(&elements, hint),
+            elements,
+            "{hint:?}"
+        );
+    }
+}
+
+#[test]
+fn lists_of_lists_with_any_size_hint() {
+    let elements = json!([["a", "b"], [], ["c"]]);
+    for hint in hints(3) {
+        assert_eq!(
+            round_trip::<list_list::Owned<text_list::Owned>>(&elements, hint),
+            elements,
+            "{hint:?}"
+        );
+    }
+    let elements = json!([[{"a": 1, "b": true}], [], [{"a": 2, "b": false}, {"a": 3, "b": true}]]);
+    for hint in hints(3) {
+        assert_eq!(
+            round_trip::<list_list::Owned<struct_list::Owned<basic::Owned>>>(&elements, hint),
+            elements,
+            "{hint:?}"
+        );
+    }
+}
+
+#[test]
+fn nested_lists_without_size_hints() {
+    // serde_json doesn't announce the length of arrays it streams
+    let json =
+        r#"{"f": ["x", null, "y"], "g": [1, 2], "h": [{"a": 1, "b": true}, {"a": 2, "b": false}]}"#;
+    let options = Options::default().null_unset_pointers(true);
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let message = TypedBuilder::from(
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(&mut deserializer, &options)
+            .unwrap(),
+    );
+    let value = to_json(message.get_root_as_reader().unwrap(), &options);
+    let expected: Value = serde_json::from_str(json).unwrap();
+    assert_eq!(value["f"], expected["f"]);
+    assert_eq!(value["g"], expected["g"]);
+    assert_eq!(value["h"], expected["h"]);
+}
+
+#[test]
+fn only_pointer_elements_are_optional() {
+    // bincode tags options, so the serializer has to agree with the deserializer on them
+    let bincode = || bincode::options().with_fixint_encoding();
+    let mut message = message::Builder::new_default();
+    message
+        .initn_root::<primitive_list::Builder<'_, u16>>(3)
+        .set(1, 7);
+    let reader = message
+        .get_root_as_reader::<primitive_list::Reader<'_, u16>>()
+        .unwrap();
+    let bytes = bincode()
+        .serialize(&CapnpSerdeReader::from(reader))
+        .unwrap();
+    assert_eq!(bytes.len(), 8 + 3 * 2);
+    let copy = TypedBuilder::from(
+        CapnpSerdeBuilder::<primitive_list::Owned<u16>>::deserialize_with_options(
+            &mut bincode::Deserializer::from_slice(&bytes, bincode()),
+            &Options::default(),
+        )
+        .unwrap(),
+    );
+    let copy = copy.get_root_as_reader().unwrap();
+    assert_eq!(copy.iter().collect::<Vec<_>>(), [0, 7, 0]);
+
+    let options = Options::default().null_unset_pointers(true);
+    let mut message = message::Builder::new_default();
+    message.initn_root::<text_list::Builder<'_>>(2).set(1, "x");
+    let reader = message
+        .get_root_as_reader::<text_list::Reader<'_>>()
+        .unwrap();
+    let reader = CapnpSerdeReader::from(reader).with_options(options.clone());
+    let bytes = bincode().serialize(&reader).unwrap();
+    let copy = TypedBuilder::from(
+        CapnpSerdeBuilder::<text_list::Owned>::deserialize_with_options(
+            &mut bincode::Deserializer::from_slice(&bytes, bincode()),
+            &options,
+        )
+        .unwrap(),
+    );
+    let copy = copy.get_root_as_reader().unwrap();
+    assert_eq!(copy.get(1).unwrap(), "x");
+    assert_eq!(to_json(copy, &options), json!([null, "x"]));
+}
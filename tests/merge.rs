@@ -0,0 +1,159 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeBuilder, ListMerge, Options, merge_into};
+use serde_json::json;
+
+use common::to_json;
+use schemas::example_capnp::{basic, complex, nested};
+
+fn nested_message() -> TypedBuilder<nested::Owned> {
+    let mut message = TypedBuilder::<nested::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_a(1);
+    root.set_c("text");
+    let mut basic = root.init_b();
+    basic.set_a(2);
+    basic.set_b(true);
+    message
+}
+
+fn complex_message() -> TypedBuilder<complex::Owned> {
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    let mut root = message.init_root();
+    root.reborrow().init_c().set_d(3);
+    root.reborrow().get_c().set_e(true);
+    let mut f = root.reborrow().init_f(3);
+    f.set(0, "x");
+    f.set(2, "z");
+    let mut h = root.init_h(1);
+    h.reborrow().get(0).set_a(5);
+    message
+}
+
+#[test]
+fn structs_and_groups_are_merged_recursively() {
+    let message = CapnpSerdeBuilder::merge_with_options(
+        nested_message(),
+        json!({"b": {"a": 7}}),
+        &Options::default(),
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &Options::default()),
+        json!({"a": 1, "b": {"a": 7, "b": true}, "c": "text"})
+    );
+
+    let message = CapnpSerdeBuilder::merge_with_options(
+        complex_message(),
+        json!({"c": {"e": false}}),
+        &Options::default(),
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let value = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!(value["c"], json!({"d": 3, "e": false}));
+}
+
+#[test]
+fn null_clears_pointers() {
+    let message = CapnpSerdeBuilder::merge_with_options(
+        nested_message(),
+        json!({"b": null, "c": null}),
+        &Options::default(),
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let root = message.get_root_as_reader().unwrap();
+    assert!(!root.has_b());
+    assert!(!root.has_c());
+    assert_eq!(root.get_a(), 1);
+}
+
+#[test]
+fn lists_are_replaced() {
+    let message = CapnpSerdeBuilder::merge_with_options(
+        complex_message(),
+        json!({"f": ["a"], "h": [{"a": 8}]}),
+        &Options::default(),
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let value = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!(value["f"], json!(["a"]));
+    assert_eq!(value["h"], json!([{"a": 8, "b": false}]));
+}
+
+#[test]
+fn lists_are_appended_to() {
+    let options = Options::default().list_merge(ListMerge::Append);
+    let message = CapnpSerdeBuilder::merge_with_options(
+        complex_message(),
+        json!({"f": [null, "w"], "h": [{"a": 9}], "g": [4]}),
+        &options,
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let value = to_json(
+        message.get_root_as_reader().unwrap(),
+        &Options::default().null_unset_pointers(true),
+    );
+    assert_eq!(value["f"], json!(["x", null, "z", null, "w"]));
+    assert_eq!(
+        value["h"],
+        json!([{"a": 5, "b": false}, {"a": 9, "b": false}])
+    );
+    assert_eq!(value["g"], json!([4]));
+}
+
+#[test]
+fn lenient_single_values_are_appended_too() {
+    let options = Options::default()
+        .list_merge(ListMerge::Append)
+        .lenient(true);
+    let message =
+        CapnpSerdeBuilder::merge_with_options(complex_message(), json!({"g": [1, 2]}), &options)
+            .unwrap();
+    let message = CapnpSerdeBuilder::merge_with_options(
+        TypedBuilder::from(message),
+        json!({"g": 7, "f": "w"}),
+        &options,
+    )
+    .unwrap();
+    let message = TypedBuilder::from(message);
+    let value = to_json(
+        message.get_root_as_reader().unwrap(),
+        &Options::default().null_unset_pointers(true),
+    );
+    assert_eq!(value["g"], json!([1, 2, 7]));
+    assert_eq!(value["f"], json!(["x", null, "z", "w"]));
+}
+
+#[test]
+fn structs_within_a_message_are_merged() {
+    // serde_json doesn't announce the length of arrays it streams
+    let options = Options::default().list_merge(ListMerge::Append);
+    let mut message = complex_message();
+    let mut deserializer = serde_json::Deserializer::from_str(r#"{"f": [null, "v"], "b": "t"}"#);
+    let unknown =
+        merge_into::<complex::Owned, _>(message.get_root().unwrap(), &mut deserializer, &options)
+            .unwrap();
+    assert!(unknown.is_empty());
+    let mut deserializer = serde_json::Deserializer::from_str(r#"{"a": 6}"#);
+    let h = message.get_root().unwrap().get_h().unwrap();
+    merge_into::<basic::Owned, _>(h.get(0), &mut deserializer, &options).unwrap();
+
+    let value = to_json(
+        message.get_root_as_reader().unwrap(),
+        &Options::default().null_unset_pointers(true),
+    );
+    assert_eq!(value["f"], json!(["x", null, "z", null, "v"]));
+    assert_eq!(value["b"], json!("t"));
+    assert_eq!(value["h"], json!([{"a": 6, "b": false}]));
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use capnp::{
+    message::TypedBuilder,
+    schema_capnp::{ElementSize, node},
+};
+use capnp_serde::{NamingConvention, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+
+fn node_message() -> TypedBuilder<node::Owned> {
+    let mut message = TypedBuilder::<node::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_id(1);
+    root.set_display_name("example.capnp:Basic");
+    let mut group = root.init_struct();
+    group.set_data_word_count(1);
+    group.set_preferred_list_encoding(ElementSize::TwoBytes);
+    message
+}
+
+#[test]
+fn conventions_round_trip() {
+    let message = node_message();
+    for (convention, display_name, word_count, encoding) in [
+        (
+            NamingConvention::Schema,
+            "displayName",
+            "dataWordCount",
+            "twoBytes",
+        ),
+        (
+            NamingConvention::CamelCase,
+            "displayName",
+            "dataWordCount",
+            "twoBytes",
+        ),
+        (
+            NamingConvention::PascalCase,
+            "DisplayName",
+            "DataWordCount",
+            "TwoBytes",
+        ),
+        (
+            NamingConvention::SnakeCase,
+            "display_name",
+            "data_word_count",
+            "two_bytes",
+        ),
+        (
+            NamingConvention::ScreamingSnakeCase,
+            "DISPLAY_NAME",
+            "DATA_WORD_COUNT",
+            "TWO_BYTES",
+        ),
+        (
+            NamingConvention::KebabCase,
+            "display-name",
+            "data-word-count",
+            "two-bytes",
+        ),
+    ] {
+        let options = Options::default().naming_convention(convention);
+        let json = to_json(message.get_root_as_reader().unwrap(), &options);
+        let object = json.as_object().unwrap();
+        assert_eq!(object[display_name], json!("example.capnp:Basic"));
+        let group = object.values().find(|value| value.is_object()).unwrap();
+        assert_eq!(group[word_count], json!(1), "{convention:?}");
+        assert!(
+            group
+                .as_object()
+                .unwrap()
+                .values()
+                .any(|value| value == encoding)
+        );
+
+        let back = from_json::<node::Owned>(json.clone(), &options).unwrap();
+        assert_eq!(to_json(back.get_root_as_reader().unwrap(), &options), json);
+    }
+}
+
+#[test]
+fn schema_names_are_only_accepted_with_the_option() {
+    let value = json!({"displayName": "name", "struct": {"preferredListEncoding": "twoBytes"}});
+    let options = Options::default().naming_convention(NamingConvention::SnakeCase);
+    assert!(from_json::<node::Owned>(value.clone(), &options).is_err());
+
+    let options = options.accept_schema_names(true);
+    let message = from_json::<node::Owned>(value, &options).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_display_name().unwrap(), "name");
+    let node::Struct(group) = reader.which().unwrap() else {
+        panic!("the struct member wasn't selected");
+    };
+    assert_eq!(
+        group.get_preferred_list_encoding().unwrap(),
+        ElementSize::TwoBytes
+    );
+}
+
+#[test]
+fn case_insensitive_names() {
+    let value = json!({"Display_Name": "name", "STRUCT": {"preferred_list_encoding": "Two_Bytes"}});
+    let options = Options::default().naming_convention(NamingConvention::SnakeCase);
+    assert!(from_json::<node::Owned>(value.clone(), &options).is_err());
+
+    let options = options.case_insensitive_names(true);
+    let message = from_json::<node::Owned>(value, &options).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert_eq!(reader.get_display_name().unwrap(), "name");
+    assert!(matches!(reader.which(), Ok(node::Struct(_))));
+}
@@ -0,0 +1,113 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{message::TypedBuilder, schema_capnp::value};
+use capnp_serde::{CapnpSerdeBuilder, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::complex;
+
+#[test]
+fn unset_pointer_fields_round_trip_as_null() {
+    let options = Options::default().null_unset_pointers(true);
+    let mut message = TypedBuilder::<complex::Owned>::new_default();
+    let mut root = message.init_root();
+    root.set_b("text");
+    root.init_g(0);
+    let expected = json!({
+        "a": null,
+        "b": "text",
+        "c": {"d": 0, "e": false},
+        "default": 12,
+        "f": null,
+        "g": [],
+        "h": null,
+        "i": "a",
+        "j": null,
+        "shouldbenull": null,
+    });
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &options),
+        expected
+    );
+
+    let back = from_json::<complex::Owned>(expected.clone(), &options).unwrap();
+    let reader = back.get_root_as_reader().unwrap();
+    assert!(!reader.has_shouldbenull());
+    assert!(!reader.has_f());
+    assert!(reader.has_g());
+    assert_eq!(to_json(reader, &options), expected);
+}
+
+#[test]
+fn null_is_accepted_for_pointers_by_default() {
+    let value = json!({"b": null, "f": ["a", null], "shouldbenull": null});
+    let message = from_json::<complex::Owned>(value, &Options::default()).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert!(!reader.has_b());
+    assert!(!reader.has_shouldbenull());
+    let f = reader.get_f().unwrap();
+    assert_eq!(f.len(), 2);
+    assert_eq!(f.get(0).unwrap(), "a");
+}
+
+#[test]
+fn null_is_rejected_for_primitives() {
+    assert!(from_json::<complex::Owned>(json!({"default": null}), &Options::default()).is_err());
+}
+
+#[test]
+fn unset_any_pointer_round_trips() {
+    let options = Options::default().null_unset_pointers(true);
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().init_any_pointer();
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(json, json!({"anyPointer": null}));
+
+    let message = from_json::<value::Owned>(json.clone(), &options).unwrap();
+    let reader = message.get_root_as_reader().unwrap();
+    assert!(matches!(reader.which().unwrap(), value::AnyPointer(_)));
+    assert_eq!(to_json(reader, &options), json);
+}
+
+#[test]
+fn any_pointer_values_are_rejected() {
+    let Err(err) = from_json::<value::Owned>(json!({"anyPointer": 5}), &Options::default()) else {
+        panic!("an AnyPointer value was accepted");
+    };
+    assert_eq!(err.path().to_string(), "anyPointer");
+}
+
+#[test]
+fn null_selects_pointer_union_members() {
+    let options = Options::default().null_unset_pointers(true);
+    for json in [
+        json!({"text": null}),
+        json!({"data": null}),
+        json!({"list": null}),
+        json!({"struct": null}),
+    ] {
+        let message = from_json::<value::Owned>(json.clone(), &options).unwrap();
+        let reader = message.get_root_as_reader().unwrap();
+        assert!(
+            !matches!(reader.which().unwrap(), value::Void(())),
+            "{json}"
+        );
+        assert_eq!(to_json(reader, &options), json);
+
+        let mut message = TypedBuilder::<value::Owned>::new_default();
+        message.init_root().set_int32(4);
+        let message =
+            CapnpSerdeBuilder::merge_with_options(message, json.clone(), &options).unwrap();
+        let message = TypedBuilder::from(message);
+        assert_eq!(
+            to_json(message.get_root_as_reader().unwrap(), &options),
+            json
+        );
+    }
+}
@@ -0,0 +1,284 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use bincode::Options as _;
+use capnp::{
+    message::{self, TypedBuilder},
+    primitive_list,
+    schema_capnp::{node, value},
+};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, NonFiniteFloats, NumericOverflow, Options};
+use serde_json::{Value, json};
+
+use common::{from_json, to_json};
+use schemas::example_capnp::complex;
+
+/// Deserializes a `Value` from JSON and serializes it again.
+fn round_trip(json: Value, options: &Options) -> Value {
+    let message = from_json::<value::Owned>(json, options).unwrap();
+    to_json(message.get_root_as_reader().unwrap(), options)
+}
+
+#[test]
+fn int64_as_string() {
+    let options = Options::default().int64_as_string(true);
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().set_uint64(u64::MAX);
+    let json = to_json(message.get_root_as_reader().unwrap(), &options);
+    assert_eq!(json, json!({"uint64": "18446744073709551615"}));
+    assert_eq!(
+        round_trip(json, &options),
+        json!({"uint64": "18446744073709551615"})
+    );
+
+    let json = json!({"int64": "-9223372036854775808"});
+    assert_eq!(round_trip(json.clone(), &options), json);
+    assert_eq!(
+        round_trip(json!({"int32": 5}), &options),
+        json!({"int32": 5})
+    );
+}
+
+#[test]
+fn decimal_strings_are_accepted_for_integers() {
+    let options = Options::default();
+    for (json, expected) in [
+        (json!({"int8": "-5"}), json!({"int8": -5})),
+        (json!({"uint16": "65535"}), json!({"uint16": 65535})),
+        (
+            json!({"uint64": "18446744073709551615"}),
+            json!({"uint64": 18446744073709551615u64}),
+        ),
+    ] {
+        assert_eq!(round_trip(json, &options), expected);
+    }
+    for json in [
+        json!({"int8": "300"}),
+        json!({"uint8": "-1"}),
+        json!({"int32": "1.5"}),
+        json!({"float64": "1.5"}),
+    ] {
+        assert!(
+            from_json::<value::Owned>(json.clone(), &options).is_err(),
+            "{json}"
+        );
+    }
+}
+
+#[test]
+fn compact_formats_read_strings_with_the_option() {
+    let options = Options::default().int64_as_string(true);
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().set_int64(-42);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options.clone());
+    let bytes = rmp_serde::to_vec(&reader).unwrap();
+    let value: Value = rmp_serde::from_slice(&bytes).unwrap();
+    assert_eq!(value, json!({"int64": "-42"}));
+
+    let back = CapnpSerdeBuilder::<value::Owned>::deserialize_with_options(
+        &mut rmp_serde::Deserializer::new(bytes.as_slice()),
+        &options,
+    )
+    .unwrap();
+    let back = TypedBuilder::from(back);
+    assert!(matches!(
+        back.get_root_as_reader().unwrap().which(),
+        Ok(value::Int64(-42))
+    ));
+}
+
+#[test]
+fn formats_that_are_not_self_describing_read_strings_for_64_bit_integers() {
+    // bincode can't read structs, so the numbers are the elements of list roots
+    let options = Options::default().int64_as_string(true);
+    let bincode = || bincode::options().with_fixint_encoding();
+
+    let mut message = message::Builder::new_default();
+    let mut list = message.initn_root::<primitive_list::Builder<'_, i64>>(2);
+    list.set(0, i64::MIN);
+    list.set(1, i64::MAX);
+    let reader = message
+        .get_root_as_reader::<primitive_list::Reader<'_, i64>>()
+        .unwrap();
+    let bytes = bincode()
+        .serialize(&CapnpSerdeReader::from(reader).with_options(options.clone()))
+        .unwrap();
+    let back = TypedBuilder::from(
+        CapnpSerdeBuilder::<primitive_list::Owned<i64>>::deserialize_with_options(
+            &mut bincode::Deserializer::from_slice(&bytes, bincode()),
+            &options,
+        )
+        .unwrap(),
+    );
+    let back = back.get_root_as_reader().unwrap();
+    assert_eq!(back.iter().collect::<Vec<_>>(), [i64::MIN, i64::MAX]);
+
+    // Narrower integers are still numbers
+    let mut message = message::Builder::new_default();
+    message
+        .initn_root::<primitive_list::Builder<'_, i32>>(1)
+        .set(0, -3);
+    let reader = message
+        .get_root_as_reader::<primitive_list::Reader<'_, i32>>()
+        .unwrap();
+    let bytes = bincode()
+        .serialize(&CapnpSerdeReader::from(reader).with_options(options.clone()))
+        .unwrap();
+    assert_eq!(bytes.len(), 8 + 4);
+    let back = TypedBuilder::from(
+        CapnpSerdeBuilder::<primitive_list::Owned<i32>>::deserialize_with_options(
+            &mut bincode::Deserializer::from_slice(&bytes, bincode()),
+            &options,
+        )
+        .unwrap(),
+    );
+    let back = back.get_root_as_reader().unwrap();
+    assert_eq!(back.iter().collect::<Vec<_>>(), [-3]);
+}
+
+#[test]
+fn non_finite_floats_as_strings() {
+    let options = Options::default().non_finite_floats(NonFiniteFloats::Strings);
+    for (value, expected) in [
+        (f64::NAN, "NaN"),
+        (f64::INFINITY, "Infinity"),
+        (f64::NEG_INFINITY, "-Infinity"),
+    ] {
+        let mut message = TypedBuilder::<value::Owned>::new_default();
+        message.init_root().set_float64(value);
+        let json = to_json(message.get_root_as_reader().unwrap(), &options);
+        assert_eq!(json, json!({"float64": expected}));
+        assert_eq!(round_trip(json.clone(), &options), json);
+    }
+    assert_eq!(
+        round_trip(json!({"float32": "-Infinity"}), &options),
+        json!({"float32": "-Infinity"})
+    );
+    assert_eq!(
+        round_trip(json!({"float32": 1.5}), &options),
+        json!({"float32": 1.5})
+    );
+}
+
+#[test]
+fn non_finite_floats_are_passed_to_the_format_by_default() {
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().set_float32(f32::NAN);
+    let json = to_json(message.get_root_as_reader().unwrap(), &Options::default());
+    assert_eq!(json, json!({"float32": null}));
+    // The strings are still accepted
+    let message =
+        from_json::<value::Owned>(json!({"float32": "NaN"}), &Options::default()).unwrap();
+    assert!(matches!(
+        message.get_root_as_reader().unwrap().which(),
+        Ok(value::Float32(value)) if value.is_nan()
+    ));
+}
+
+#[test]
+fn non_finite_floats_can_fail_the_serialization() {
+    let options = Options::default().non_finite_floats(NonFiniteFloats::Error);
+    let mut message = TypedBuilder::<value::Owned>::new_default();
+    message.init_root().set_float64(f64::INFINITY);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options.clone());
+    let err = serde_json::to_value(&reader).unwrap_err();
+    assert!(
+        err.to_string().contains("Infinity can't be serialized"),
+        "{err}"
+    );
+
+    message.init_root().set_float64(1.0);
+    let reader =
+        CapnpSerdeReader::from(message.get_root_as_reader().unwrap()).with_options(options);
+    assert_eq!(
+        serde_json::to_value(&reader).unwrap(),
+        json!({"float64": 1.0})
+    );
+}
+
+#[test]
+fn overflow_is_an_error_by_default() {
+    let options = Options::default();
+    for json in [
+        json!({"uint16": 70000}),
+        json!({"int8": -129}),
+        json!({"uint64": -1}),
+        json!({"float32": 1e300}),
+    ] {
+        assert!(
+            from_json::<value::Owned>(json.clone(), &options).is_err(),
+            "{json}"
+        );
+    }
+    let json = json!({"struct": {"dataWordCount": 70000}});
+    let Err(err) = from_json::<node::Owned>(json, &options) else {
+        panic!("an overflowing field was accepted");
+    };
+    assert_eq!(err.path().to_string(), "struct.dataWordCount");
+}
+
+#[test]
+fn overflowing_list_elements_are_errors() {
+    let Err(err) = from_json::<complex::Owned>(json!({"g": [1, 70000]}), &Options::default())
+    else {
+        panic!("an overflowing element was accepted");
+    };
+    assert_eq!(err.path().to_string(), "g[1]");
+}
+
+#[test]
+fn overflow_saturates() {
+    let options = Options::default().numeric_overflow(NumericOverflow::Saturate);
+    assert_eq!(
+        round_trip(json!({"uint16": 70000}), &options),
+        json!({"uint16": 65535})
+    );
+    assert_eq!(
+        round_trip(json!({"uint32": -5}), &options),
+        json!({"uint32": 0})
+    );
+    assert_eq!(
+        round_trip(json!({"int8": "-1000"}), &options),
+        json!({"int8": -128})
+    );
+    assert_eq!(
+        round_trip(json!({"float32": 1e300}), &options),
+        json!({"float32": f32::MAX})
+    );
+}
+
+#[test]
+fn overflow_wraps() {
+    let options = Options::default().numeric_overflow(NumericOverflow::Wrap);
+    assert_eq!(
+        round_trip(json!({"uint16": 70000}), &options),
+        json!({"uint16": 4464})
+    );
+    assert_eq!(
+        round_trip(json!({"uint8": -1}), &options),
+        json!({"uint8": 255})
+    );
+    assert!(from_json::<value::Owned>(json!({"float32": 1e300}), &options).is_err());
+}
+
+#[test]
+fn floats_are_only_accepted_for_integers_if_exact() {
+    assert_eq!(
+        round_trip(json!({"int8": 3.0}), &Options::default()),
+        json!({"int8": 3})
+    );
+    for policy in [
+        NumericOverflow::Error,
+        NumericOverflow::Saturate,
+        NumericOverflow::Wrap,
+    ] {
+        let options = Options::default().numeric_overflow(policy);
+        assert!(from_json::<value::Owned>(json!({"int8": 3.7}), &options).is_err());
+    }
+}
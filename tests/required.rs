@@ -0,0 +1,95 @@
+mod common;
+mod schemas {
+    pub mod annotated_capnp {
+        include!(concat!(env!("OUT_DIR"), "/annotated_capnp.rs"));
+    }
+    #[allow(dead_code)]
+    pub mod json_capnp {
+        include!(concat!(env!("OUT_DIR"), "/capnp/compat/json_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeBuilder, Options, UnionRepresentation};
+use serde_json::json;
+
+use common::from_json;
+use schemas::annotated_capnp::{req, wrapper};
+
+fn error_message<T>(result: Result<T, capnp_serde::Error>) -> String {
+    match result {
+        Ok(_) => panic!("a missing field was accepted"),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+fn complete_documents_are_accepted() {
+    let value = json!({"name": "n", "all": {"x": 1, "y": 2}, "a": 1});
+    assert!(from_json::<req::Owned>(value, &Options::default()).is_ok());
+}
+
+#[test]
+fn missing_fields_are_listed() {
+    assert_eq!(
+        error_message(from_json::<req::Owned>(json!({}), &Options::default())),
+        "missing required fields in `annotated.capnp:Req`: `name`, one of `a`/`b`/`c`"
+    );
+}
+
+#[test]
+fn required_fields() {
+    let value = json!({"all": {"x": 1, "y": 2}, "a": 1});
+    assert_eq!(
+        error_message(from_json::<req::Owned>(value, &Options::default())),
+        "missing required fields in `annotated.capnp:Req`: `name`"
+    );
+}
+
+#[test]
+fn nested_groups_requiring_all_fields() {
+    let value = json!({"req": {"name": "n", "all": {"x": 1}, "b": null}});
+    let Err(err) = from_json::<wrapper::Owned>(value, &Options::default()) else {
+        panic!("a missing field was accepted");
+    };
+    assert_eq!(err.path().to_string(), "req.all");
+    assert_eq!(
+        err.to_string(),
+        "req.all (annotated.capnp:Req.all): missing required fields in `annotated.capnp:Req.all`: `y`"
+    );
+}
+
+#[test]
+fn missing_union_members() {
+    let value = json!({"name": "n", "all": {"x": 1, "y": 2}});
+    assert_eq!(
+        error_message(from_json::<req::Owned>(value, &Options::default())),
+        "missing required fields in `annotated.capnp:Req`: one of `a`/`b`/`c`"
+    );
+}
+
+#[test]
+fn required_union_members_only_apply_if_selected() {
+    let options = Options::default().union_representation(UnionRepresentation::internal());
+    let value = json!({"name": "n", "all": {"x": 1, "y": 2}, "which": "b"});
+    assert!(from_json::<req::Owned>(value, &options).is_ok());
+
+    let value = json!({"name": "n", "all": {"x": 1, "y": 2}, "which": "c"});
+    assert_eq!(
+        error_message(from_json::<req::Owned>(value, &options)),
+        "missing required fields in `annotated.capnp:Req`: `c`"
+    );
+}
+
+#[test]
+fn merged_documents_may_leave_out_required_fields() {
+    let mut message = TypedBuilder::<req::Owned>::new_default();
+    message.init_root().set_name("n");
+    let message =
+        CapnpSerdeBuilder::merge_with_options(message, json!({"age": 3}), &Options::default())
+            .unwrap();
+    let message = TypedBuilder::from(message);
+    let root = message.get_root_as_reader().unwrap();
+    assert_eq!(root.get_name().unwrap(), "n");
+    assert_eq!(root.get_age(), 3);
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use capnp::{any_pointer, data, message::TypedBuilder, text};
+use capnp_serde::{CapnpSerdeBuilder, CapnpSerdeReader, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+
+#[test]
+fn text_roots() {
+    let options = Options::default();
+    let message = from_json::<text::Owned>(json!("hello"), &options).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    assert_eq!(root.to_str().unwrap(), "hello");
+    assert_eq!(to_json(root, &options), json!("hello"));
+}
+
+#[test]
+fn data_roots() {
+    let options = Options::default();
+    let message = from_json::<data::Owned>(json!("AQID"), &options).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    assert_eq!(root, &[1, 2, 3]);
+    assert_eq!(to_json(root, &options), json!("AQID"));
+}
+
+#[test]
+fn unset_blob_roots_are_null() {
+    let options = Options::default();
+    let message = from_json::<data::Owned>(json!(null), &options).unwrap();
+    let root = message.borrow_inner().get_root_as_reader().unwrap();
+    let reader = CapnpSerdeReader::from_root::<data::Owned>(root).unwrap();
+    assert_eq!(serde_json::to_value(&reader).unwrap(), json!(null));
+
+    let message = from_json::<text::Owned>(json!(null), &options).unwrap();
+    let root = message.borrow_inner().get_root_as_reader().unwrap();
+    let reader = CapnpSerdeReader::from_root::<text::Owned>(root).unwrap();
+    assert_eq!(serde_json::to_value(&reader).unwrap(), json!(null));
+
+    // Set roots are read with their type
+    let message = from_json::<data::Owned>(json!(""), &options).unwrap();
+    let root = message.borrow_inner().get_root_as_reader().unwrap();
+    let reader = CapnpSerdeReader::from_root::<data::Owned>(root).unwrap();
+    assert_eq!(serde_json::to_value(&reader).unwrap(), json!(""));
+}
+
+#[test]
+fn oversized_blob_roots_are_rejected() {
+    // MessagePack bin32 and str32 values whose bytes are untouched pages of zeros, which aren't
+    // backed by memory. The text doesn't fit with its NUL terminator.
+    for (marker, len) in [(0xc6, 1 << 29), (0xdb, (1 << 29) - 1)] {
+        let mut bytes = vec![0; 5 + len as usize];
+        bytes[0] = marker;
+        bytes[1..5].copy_from_slice(&u32::to_be_bytes(len));
+        let result = if marker == 0xc6 {
+            rmp_serde::from_slice::<CapnpSerdeBuilder<data::Owned>>(&bytes).map(drop)
+        } else {
+            rmp_serde::from_slice::<CapnpSerdeBuilder<text::Owned>>(&bytes).map(drop)
+        };
+        let Err(err) = result else {
+            panic!("a blob of {len} bytes was accepted");
+        };
+        assert!(err.to_string().contains("too large"), "{err}");
+    }
+}
+
+#[test]
+fn any_pointer_roots_can_only_be_null() {
+    let options = Options::default();
+    let message = from_json::<any_pointer::Owned>(json!(null), &options).unwrap();
+    let root = message.get_root_as_reader().unwrap();
+    assert!(root.is_null());
+    assert_eq!(to_json(root, &options), json!(null));
+    assert!(from_json::<any_pointer::Owned>(json!({"a": 1}), &options).is_err());
+}
+
+#[test]
+fn null_clears_merged_roots() {
+    let mut message = TypedBuilder::<text::Owned>::new_default();
+    message.initn_root(5).push_str("hello");
+    let message =
+        CapnpSerdeBuilder::merge_with_options(message, json!(null), &Options::default()).unwrap();
+    let message = TypedBuilder::from(message);
+    let root: any_pointer::Reader<'_> = message.borrow_inner().get_root_as_reader().unwrap();
+    assert!(root.is_null());
+}
@@ -0,0 +1,83 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::{message::TypedBuilder, schema_capnp::node};
+use capnp_serde::{CapnpSerdeBuilder, NamingConvention, Options};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::{complex, unions};
+
+/// Deserializes a JSON string, which may repeat keys unlike `serde_json::Value`.
+fn from_str<O>(json: &str, options: &Options) -> Result<TypedBuilder<O>, capnp_serde::Error>
+where
+    O: capnp::traits::Owned + capnp::introspect::Introspect + 'static,
+    for<'a> O::Builder<'a>: Into<capnp::dynamic_value::Builder<'a>>,
+{
+    CapnpSerdeBuilder::<O>::deserialize_with_options(
+        &mut serde_json::Deserializer::from_str(json),
+        options,
+    )
+    .map(TypedBuilder::from)
+}
+
+fn error_message<T>(result: Result<T, capnp_serde::Error>) -> String {
+    match result {
+        Ok(_) => panic!("the conflict wasn't detected"),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+fn duplicate_keys_are_rejected() {
+    let json = r#"{"b": "first", "c": {"d": 1}, "b": "second"}"#;
+    let message = error_message(from_str::<complex::Owned>(json, &Options::default()));
+    assert!(
+        message.starts_with("b (Text): duplicate key `b` in `example.capnp:Complex`"),
+        "{message}"
+    );
+
+    let message = from_str::<complex::Owned>(json, &Options::default().strict(false)).unwrap();
+    assert_eq!(
+        message.get_root_as_reader().unwrap().get_b().unwrap(),
+        "second"
+    );
+}
+
+#[test]
+fn aliases_of_the_same_field_are_rejected() {
+    let options = Options::default()
+        .naming_convention(NamingConvention::SnakeCase)
+        .accept_schema_names(true);
+    let value = json!({"display_name": "a", "displayName": "b"});
+    let message = error_message(from_json::<node::Owned>(value, &options));
+    assert!(
+        message.starts_with(
+            "display_name (Text): the keys `displayName` and `display_name` set the same field in"
+        ),
+        "{message}"
+    );
+}
+
+#[test]
+fn conflicting_union_members_are_rejected() {
+    let value = json!({"d": 1, "e": null});
+    assert_eq!(
+        error_message(from_json::<unions::Owned>(
+            value.clone(),
+            &Options::default()
+        )),
+        "conflicting union members `d` and `e` in `example.capnp:Unions`"
+    );
+
+    let options = Options::default().strict(false);
+    let message = from_json::<unions::Owned>(value, &options).unwrap();
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &options),
+        json!({"named": {"a": 0}, "e": null})
+    );
+}
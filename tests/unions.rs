@@ -0,0 +1,93 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{Options, UnionRepresentation};
+use serde_json::{Value, json};
+
+use common::{from_json, to_json};
+use schemas::example_capnp::unions;
+
+fn unions_message() -> TypedBuilder<unions::Owned> {
+    let mut message = TypedBuilder::<unions::Owned>::new_default();
+    let mut root = message.init_root();
+    root.reborrow().init_named().set_a(42);
+    root.set_d(84);
+    message
+}
+
+/// Serializes the message, checks the JSON and reads it back.
+fn round_trip(message: &TypedBuilder<unions::Owned>, options: &Options, expected: Value) {
+    let json = to_json(message.get_root_as_reader().unwrap(), options);
+    assert_eq!(json, expected);
+    let back = from_json::<unions::Owned>(json, options).unwrap();
+    assert_eq!(
+        to_json(back.get_root_as_reader().unwrap(), options),
+        expected
+    );
+}
+
+#[test]
+fn external() {
+    round_trip(
+        &unions_message(),
+        &Options::default(),
+        json!({"named": {"a": 42}, "d": 84}),
+    );
+}
+
+#[test]
+fn internal() {
+    let options = Options::default().union_representation(UnionRepresentation::internal());
+    round_trip(
+        &unions_message(),
+        &options,
+        json!({"named": {"which": "a", "a": 42}, "which": "d", "d": 84}),
+    );
+}
+
+#[test]
+fn adjacent() {
+    let options = Options::default().union_representation(UnionRepresentation::adjacent());
+    round_trip(
+        &unions_message(),
+        &options,
+        json!({"named": {"type": "a", "value": 42}, "type": "d", "value": 84}),
+    );
+}
+
+#[test]
+fn void_members_select_the_discriminant() {
+    let mut message = TypedBuilder::<unions::Owned>::new_default();
+    let mut root = message.init_root();
+    root.reborrow().init_named().set_b(());
+    root.set_e(());
+    for (representation, expected) in [
+        (
+            UnionRepresentation::External,
+            json!({"named": {"b": null}, "e": null}),
+        ),
+        (
+            UnionRepresentation::internal(),
+            json!({"named": {"which": "b"}, "which": "e"}),
+        ),
+        (
+            UnionRepresentation::adjacent(),
+            json!({"named": {"type": "b"}, "type": "e"}),
+        ),
+    ] {
+        let options = Options::default().union_representation(representation);
+        round_trip(&message, &options, expected.clone());
+        let back = from_json::<unions::Owned>(expected, &options).unwrap();
+        let reader = back.get_root_as_reader().unwrap();
+        assert!(matches!(
+            reader.get_named().which(),
+            Ok(unions::named::B(()))
+        ));
+        assert!(matches!(reader.which(), Ok(unions::E(()))));
+    }
+}
@@ -0,0 +1,70 @@
+mod common;
+mod schemas {
+    pub mod example_capnp {
+        include!(concat!(env!("OUT_DIR"), "/example_capnp.rs"));
+    }
+}
+
+use capnp::message::TypedBuilder;
+use capnp_serde::{CapnpSerdeBuilder, Options, UnknownFields};
+use serde_json::json;
+
+use common::{from_json, to_json};
+use schemas::example_capnp::complex;
+
+fn document() -> serde_json::Value {
+    json!({
+        "b": "text",
+        "new": {"nested": [1, 2]},
+        "c": {"d": 3, "f": true},
+        "h": [{"a": 1}, {"a": 2, "c": null}],
+    })
+}
+
+#[test]
+fn unknown_fields_are_rejected_by_default() {
+    let Err(err) = from_json::<complex::Owned>(document(), &Options::default()) else {
+        panic!("an unknown field was accepted");
+    };
+    assert_eq!(
+        err.to_string(),
+        "c (example.capnp:Complex.c): unknown field `f`"
+    );
+}
+
+#[test]
+fn unknown_fields_can_be_ignored() {
+    let options = Options::default().unknown_fields(UnknownFields::Ignore);
+    let message = from_json::<complex::Owned>(document(), &options).unwrap();
+    assert_eq!(
+        to_json(message.get_root_as_reader().unwrap(), &options),
+        json!({
+            "b": "text",
+            "c": {"d": 3, "e": false},
+            "default": 12,
+            "h": [{"a": 1, "b": false}, {"a": 2, "b": false}],
+            "i": "a",
+        })
+    );
+}
+
+#[test]
+fn unknown_fields_can_be_collected() {
+    let options = Options::default().unknown_fields(UnknownFields::Collect);
+    let builder =
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(document(), &options)
+            .unwrap();
+    let paths: Vec<_> = builder
+        .unknown_fields()
+        .iter()
+        .map(|path| path.to_string())
+        .collect();
+    assert_eq!(paths, ["c.f", "h[1].c", "new"]);
+    let message = TypedBuilder::from(builder);
+    assert_eq!(message.get_root_as_reader().unwrap().get_c().get_d(), 3);
+
+    let builder =
+        CapnpSerdeBuilder::<complex::Owned>::deserialize_with_options(json!({"b": "x"}), &options)
+            .unwrap();
+    assert!(builder.unknown_fields().is_empty());
+}